@@ -2,32 +2,343 @@ extern crate image;
 extern crate imageproc;
 
 use crate::cli;
-use image::{DynamicImage, FilterType::*, GenericImage, GenericImageView, ImageOutputFormat};
-use std::{collections::HashMap, sync::mpsc};
+use crate::error::ImageneError;
+use crate::pipeline::Images;
+use image::{
+    DynamicImage, FilterType, FilterType::*, GenericImage, GenericImageView, ImageBuffer,
+    ImageOutputFormat, ImageRgba8, Rgba,
+};
+use imageproc::affine::{self, Interpolation};
+use std::collections::HashMap;
+use std::str::FromStr;
 
+mod composite;
+mod font;
+mod grid;
 mod text;
 
+#[derive(Clone, Debug)]
 pub enum Action {
     Invert,
     Blur(f32),
     Brightness(i32),
     Contrast(f32),
+    /// Rotate by 90 degree steps, where `Up` leaves the image as is
     Rotate(Direction),
-    Crop(u32, u32, u32, u32),
+    /// Rotate clockwise by any amount of degrees, filling the uncovered area with a color
+    RotateBy(f32, Canvas, (f32, f32, f32, f32)),
+    /// Cut out the area at x and y with a width and height
+    Crop(Length, Length, Length, Length),
     Unsharpen(f32, i32),
-    Scale(u32, u32),
-    Append(String, Direction),
+    /// Resize to a width and height where 0 keeps the aspect ratio, with a filter overriding the
+    /// one used for the whole pipeline. The mode decides what happens to the aspect ratio when
+    /// both are given.
+    Scale(Length, Length, Option<FilterType>, ResizeMode),
+    /// Add an image next to this one, resized to fit
+    Append {
+        /// Name of the image to add, which can also be the source image itself
+        image: String,
+        /// Which side of this image it goes on
+        direction: Direction,
+        /// Overrides the filter used for the whole pipeline
+        filter: Option<FilterType>,
+        /// Pixels between the images
+        gutter: u32,
+        /// Color of the gutter and of whatever the images don't cover
+        background: (f32, f32, f32, f32),
+        /// Keep the size of the added image and align both along the shared edge instead
+        align: Option<Align>,
+    },
+    /// Draw another image on top of this one at a position, with an opacity and blend mode. The
+    /// position is resolved against the space left next to the image on top, so `50%` centers
+    /// it and `-20` keeps it 20 pixels from the right or bottom edge. With a tile it's repeated
+    /// over the whole image instead.
+    Overlay(String, (Length, Length), f32, Blend, Option<Tile>),
+    /// Add margins in a color to the top, right, bottom and left
+    Pad(Length, Length, Length, Length, (f32, f32, f32, f32)),
+    /// Place the image on a larger canvas of a color, where the gravity decides where it goes
+    Extend(Length, Length, Gravity, (f32, f32, f32, f32)),
+    /// Lay out this image and the named ones as a contact sheet with a number of columns, spacing
+    /// and background color. Every cell is the size of this image and captions with the file
+    /// name are added in a font and size if given.
+    Grid(
+        Vec<String>,
+        u32,
+        u32,
+        (f32, f32, f32, f32),
+        Option<(String, FontSize)>,
+    ),
     Flip(Orientation),
     Format(image::ImageOutputFormat),
-    Watermark(String, (f32, f32), (f32, f32, f32, f32), (String, f32)),
+    /// Which metadata of the input image is written to the output, the last one wins
+    Metadata(Metadata),
+    /// Filter used by every action that resizes, the last one wins
+    Filter(FilterType),
+    /// Draw text laid out in lines and with effects that keep it readable
+    Watermark {
+        /// Text with placeholders such as `{filename}` that are filled in for each image
+        text: String,
+        position: (Length, Length),
+        color: (f32, f32, f32, f32),
+        /// Font path or family name, empty for the bundled font, and its size
+        font: (String, FontSize),
+        layout: TextLayout,
+        effects: TextEffects,
+        /// Repeat the text over the whole image instead of drawing it at the position
+        tile: Option<Tile>,
+    },
 }
 
+#[derive(Clone, Debug)]
 pub enum Orientation {
     Vertical,
     Horizontal,
 }
 
-#[derive(Eq, PartialEq)]
+/// What happens to the canvas when an image is rotated by an arbitrary angle
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Canvas {
+    /// Grow the canvas so the whole rotated image fits
+    Expand,
+    /// Keep the original size, cutting off the corners
+    Crop,
+}
+
+/// Which EXIF, XMP and ICC metadata of the input image is carried over to JPEG and PNG outputs
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Metadata {
+    Keep,
+    Strip,
+    /// Only keep the color profile
+    IccOnly,
+}
+
+/// A position or size along one side of an image, resolved against the length of that side when
+/// the action is applied
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Length {
+    Pixels(u32),
+    /// Percent of the side
+    Percent(f32),
+    /// Pixels less than the whole side, which counts from the right or bottom edge for positions
+    FromEnd(u32),
+}
+
+impl Length {
+    pub fn resolve(self, side: u32) -> u32 {
+        match self {
+            Length::Pixels(pixels) => pixels,
+            Length::Percent(percent) => (side as f32 * percent / 100.0).round().max(0.0) as u32,
+            Length::FromEnd(pixels) => side.saturating_sub(pixels),
+        }
+    }
+}
+
+/// Parses `50%`, `20`, `20px` or `-20` for 20 pixels from the end. Percentages can't be negative.
+impl FromStr for Length {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(percent) = s.strip_suffix('%') {
+            return match percent.parse::<f32>() {
+                Ok(percent) if percent.is_finite() && percent >= 0.0 => {
+                    Ok(Length::Percent(percent))
+                }
+                _ => Err(()),
+            };
+        }
+        let pixels = s.strip_suffix("px").unwrap_or(s);
+        match pixels.strip_prefix('-') {
+            Some(pixels) => pixels.parse().map(Length::FromEnd),
+            None => pixels.parse().map(Length::Pixels),
+        }
+        .map_err(|_| ())
+    }
+}
+
+/// Size of text, resolved against the image it's drawn on when the action is applied
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FontSize {
+    /// Tenths of the image width, so 1.0 is a tenth of it
+    Relative(f32),
+    Pixels(f32),
+    /// Points at a DPI
+    Points(f32, f32),
+    /// Percent of the image width
+    PercentWidth(f32),
+    /// Percent of the image height
+    PercentHeight(f32),
+}
+
+impl FontSize {
+    /// Size in pixels on an image of `(w, h)`
+    pub fn resolve(self, (w, h): (u32, u32)) -> f32 {
+        match self {
+            FontSize::Relative(scale) => w as f32 * scale * 0.1,
+            FontSize::Pixels(pixels) => pixels,
+            FontSize::Points(points, dpi) => points * dpi / 72.0,
+            FontSize::PercentWidth(percent) => w as f32 * percent / 100.0,
+            FontSize::PercentHeight(percent) => h as f32 * percent / 100.0,
+        }
+    }
+}
+
+/// Parses `1.5` relative to the width, `24px`, `12pt` at 72 DPI, `5%w` or `5%h`. Sizes have to be
+/// larger than zero.
+impl FromStr for FontSize {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        type Unit = fn(f32) -> FontSize;
+        let units: [(&str, Unit); 4] = [
+            ("px", FontSize::Pixels),
+            ("pt", |points| FontSize::Points(points, 72.0)),
+            ("%w", FontSize::PercentWidth),
+            ("%h", FontSize::PercentHeight),
+        ];
+        let (value, unit) = units
+            .iter()
+            .find_map(|(suffix, unit)| s.strip_suffix(suffix).map(|value| (value, *unit)))
+            .unwrap_or((s, FontSize::Relative));
+        match value.parse::<f32>() {
+            Ok(size) if size.is_finite() && size > 0.0 => Ok(unit(size)),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How an image is fit into a box when resized to both a width and a height
+#[derive(Clone, Debug)]
+pub enum ResizeMode {
+    /// Stretch to exactly the size of the box
+    Fill,
+    /// Keep the aspect ratio and fit inside of the box, which may leave it smaller on one side
+    Fit,
+    /// Keep the aspect ratio and cover the whole box, cutting off what sticks out on the side
+    /// opposite of the gravity
+    Cover(Gravity),
+    /// Fit inside of the box and fill the rest of it with a color
+    Pad(Gravity, (f32, f32, f32, f32)),
+}
+
+/// How the colors of an image drawn on top of another are combined with the colors below
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Blend {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    Difference,
+}
+
+/// How lines of text are placed relative to the position of the text
+#[derive(Clone, Debug)]
+pub struct TextLayout {
+    /// Which point of the box around the text goes on the position
+    pub anchor: Gravity,
+    /// How lines of different widths are aligned with each other
+    pub align: Align,
+    /// Wrap lines at spaces to stay within this width
+    pub max_width: Option<Length>,
+    /// Distance between lines relative to the height of a line
+    pub spacing: f32,
+}
+
+impl Default for TextLayout {
+    fn default() -> Self {
+        TextLayout {
+            anchor: Gravity::NorthWest,
+            align: Align::Start,
+            max_width: None,
+            spacing: 1.0,
+        }
+    }
+}
+
+/// Effects drawn below text, each in its own color
+#[derive(Clone, Debug, Default)]
+pub struct TextEffects {
+    /// Stroke around every glyph that is this many pixels wide
+    pub outline: Option<(u32, (f32, f32, f32, f32))>,
+    pub shadow: Option<Shadow>,
+    /// Box around all of the text with this many pixels of padding
+    pub background: Option<(u32, (f32, f32, f32, f32))>,
+}
+
+/// A copy of text drawn below it
+#[derive(Clone, Debug)]
+pub struct Shadow {
+    /// How far it is moved to the right and down
+    pub offset: (i32, i32),
+    /// Sigma of the gaussian blur, 0 for a sharp shadow
+    pub blur: f32,
+    pub color: (f32, f32, f32, f32),
+}
+
+/// How copies of a watermark are repeated across an image, in staggered rows
+#[derive(Clone, Debug)]
+pub struct Tile {
+    /// Clockwise rotation of the rows in degrees
+    pub angle: f32,
+    /// Pixels between neighbouring copies
+    pub spacing: u32,
+    pub opacity: f32,
+}
+
+/// Where to place something smaller along a side, from the top or left
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Align {
+    Start,
+    Center,
+    End,
+}
+
+impl Align {
+    /// Where something of length `inner` starts along a side of length `outer`
+    pub fn offset(&self, outer: u32, inner: u32) -> u32 {
+        match *self {
+            Align::Start => 0,
+            Align::Center => outer.saturating_sub(inner) / 2,
+            Align::End => outer.saturating_sub(inner),
+        }
+    }
+}
+
+/// Which part of an image to keep in place when it's cut or placed on a larger canvas
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Gravity {
+    Center,
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+}
+
+impl Gravity {
+    /// Where to place something of size `inner` inside of something of size `outer`
+    pub fn offset(&self, (ow, oh): (u32, u32), (iw, ih): (u32, u32)) -> (u32, u32) {
+        let (x, y) = match *self {
+            Gravity::Center => (1, 1),
+            Gravity::North => (1, 0),
+            Gravity::NorthEast => (2, 0),
+            Gravity::East => (2, 1),
+            Gravity::SouthEast => (2, 2),
+            Gravity::South => (1, 2),
+            Gravity::SouthWest => (0, 2),
+            Gravity::West => (0, 1),
+            Gravity::NorthWest => (0, 0),
+        };
+        (ow.saturating_sub(iw) * x / 2, oh.saturating_sub(ih) * y / 2)
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
 pub enum Direction {
     Up,
     Down,
@@ -35,23 +346,43 @@ pub enum Direction {
     Right,
 }
 
-#[derive(Hash, Eq, PartialEq, Debug)]
+#[derive(Clone, Hash, Eq, PartialEq, Debug)]
 pub enum Flag {
+    /// Same as a `Filter(Lanczos3)` action
     Lanczos3,
+    DryRun,
+    Batch,
+    NoAutoOrient,
 }
 
+/// Apply every action in order. `arguments` holds where each action was given, such as
+/// `argument 3`, which geometry errors are reported with.
 pub fn apply_actions(
     infile: &str,
+    mut image: DynamicImage,
     mut out_format: ImageOutputFormat,
-    actions: Vec<Action>,
-    flags: HashMap<Flag, bool>,
-    mut images: HashMap<String, mpsc::Receiver<DynamicImage>>,
-) -> (DynamicImage, ImageOutputFormat) {
-    let mut image = images.get_mut(infile).unwrap().recv().unwrap();
-    let mut extra_images: HashMap<String, DynamicImage> = HashMap::new();
-
-    for action in actions {
-        match action {
+    actions: &[Action],
+    arguments: &[String],
+    flags: &HashMap<Flag, bool>,
+    images: &mut Images,
+) -> Result<(DynamicImage, ImageOutputFormat), ImageneError> {
+    // An explicit filter wins over the lanczos3 flag, otherwise quality is worth a slower resize
+    let filter = actions
+        .iter()
+        .rev()
+        .find_map(|action| match *action {
+            Action::Filter(filter) => Some(filter),
+            _ => None,
+        })
+        .unwrap_or(if cli::flag_is_enabled(flags.get(&Flag::Lanczos3)) {
+            Lanczos3
+        } else {
+            CatmullRom
+        });
+
+    for (action, argument) in actions.iter().zip(arguments) {
+        let geometry = |e: String| ImageneError::Geometry(argument.to_owned(), e);
+        match *action {
             Action::Invert => image.invert(),
 
             Action::Contrast(c) => image = image.adjust_contrast(c),
@@ -62,29 +393,21 @@ pub fn apply_actions(
 
             Action::Unsharpen(sigma, threshold) => image = image.unsharpen(sigma, threshold),
 
-            Action::Crop(x, y, w, h) => image = image.crop(x, y, w, h),
-
-            Action::Rotate(d) => {
-                image = match d {
-                    Direction::Right => image.rotate90(),
-                    Direction::Left => image.rotate270(),
-                    Direction::Down => image.rotate180(),
-                    Direction::Up => image,
-                }
+            Action::Crop(x, y, w, h) => {
+                let (x, y) = resolve((x, y), image.dimensions());
+                let (w, h) = resolve((w, h), image.dimensions());
+                image = image.crop(x, y, w, h)
             }
 
-            Action::Flip(orientation) => match orientation {
-                Orientation::Vertical => image = image.flipv(),
-                Orientation::Horizontal => image = image.fliph(),
-            },
+            Action::Rotate(_) | Action::Flip(_) => image = rotate_or_flip(image, action),
 
-            Action::Scale(w, h) => {
-                // Grab which algorithm to use from flag
-                let algorithm = if cli::flag_is_enabled(flags.get(&Flag::Lanczos3)) {
-                    Lanczos3
-                } else {
-                    Nearest
-                };
+            Action::RotateBy(degrees, ref canvas, color) => {
+                image = rotate_by(image, degrees, canvas, rgba8(color))
+            }
+
+            Action::Scale(w, h, f, ref mode) => {
+                let algorithm = f.unwrap_or(filter);
+                let (w, h) = resolve((w, h), image.dimensions());
                 if w == 0 {
                     image = image.resize(std::u32::MAX, h, algorithm);
                     continue;
@@ -93,89 +416,667 @@ pub fn apply_actions(
                     image = image.resize(w, std::u32::MAX, algorithm);
                     continue;
                 }
-                image = image.resize_exact(w, h, algorithm)
-            }
-
-            Action::Watermark(text, (pos_x, pos_y), rgba, (font_name, font_size)) => {
-                image = text::draw(
-                    image,
-                    (
-                        (rgba.0 * 255.0) as u8,
-                        (rgba.1 * 255.0) as u8,
-                        (rgba.2 * 255.0) as u8,
-                        (rgba.3 * 255.0) as u8,
-                    ),
-                    &font_name,
-                    (pos_x, pos_y),
-                    font_size,
-                    &text,
-                );
+                image = scale(image, (w, h), algorithm, mode)
             }
 
-            Action::Append(filename, direction) => {
-                // Grab which algorithm to use from flag
-                let algorithm = if cli::flag_is_enabled(flags.get(&Flag::Lanczos3)) {
-                    Lanczos3
-                } else {
-                    Nearest
+            Action::Watermark {
+                ref text,
+                position,
+                color: rgba,
+                font: (ref font_name, font_size),
+                ref layout,
+                ref effects,
+                ref tile,
+            } => {
+                let rgba = rgba8(rgba).data;
+                let rgba = (rgba[0], rgba[1], rgba[2], rgba[3]);
+                let text = text::expand(text, infile, image.dimensions(), images);
+                image = match *tile {
+                    None => {
+                        let (pos_x, pos_y) = resolve(position, image.dimensions());
+                        let font = (font_name.as_str(), font_size);
+                        text::draw(image, rgba, font, (pos_x, pos_y), &text, layout, effects)?
+                    }
+                    Some(ref tile) => {
+                        // Drawn once in the middle of a transparent copy, so sizes and wrapping
+                        // are relative to the image like they are without a tile
+                        let (w, h) = image.dimensions();
+                        let layout = TextLayout {
+                            anchor: Gravity::Center,
+                            ..layout.clone()
+                        };
+                        let stamp = text::draw(
+                            ImageRgba8(ImageBuffer::new(w, h)),
+                            rgba,
+                            (font_name, font_size),
+                            (w / 2, h / 2),
+                            &text,
+                            &layout,
+                            effects,
+                        )?;
+                        composite::tile(image, &stamp, tile, 1.0, &Blend::Normal)
+                    }
                 };
+            }
 
-                // The appendable image can either be same as source, an image that hasn't been
-                // initialized, or an already initialized one. This handles all 3 cases
-                let mut image_to_append;
-                if filename == infile {
-                    image_to_append = image.clone();
-                } else {
-                    if !extra_images.contains_key(&filename) {
-                        extra_images.insert(
-                            filename.clone(),
-                            images.get_mut(&filename).unwrap().recv().unwrap(),
-                        );
-                    }
-                    image_to_append = extra_images.get_mut(&filename).unwrap().clone();
-                }
+            Action::Append {
+                image: ref filename,
+                ref direction,
+                filter: f,
+                gutter,
+                background: color,
+                ref align,
+            } => {
+                let algorithm = f.unwrap_or(filter);
 
-                // Appended image inherits size of original image
-                let mut parent = if direction == Direction::Up || direction == Direction::Down {
-                    // Vertically append
-                    image_to_append =
-                        image_to_append.resize(image.width(), std::u32::MAX, algorithm);
-                    image::DynamicImage::new_rgba8(
-                        image.width(),
-                        image.height() + image_to_append.height(),
-                    )
+                // The appendable image can either be the source itself or another named image
+                let mut image_to_append = if filename == infile {
+                    image.clone()
                 } else {
-                    // Horizontally append
-                    image_to_append =
-                        image_to_append.resize(std::u32::MAX, image.height(), algorithm);
-                    image::DynamicImage::new_rgba8(
-                        image.width() + image_to_append.width(),
-                        image.height(),
-                    )
+                    images.get(filename)?.clone()
                 };
+                let vertical = *direction == Direction::Up || *direction == Direction::Down;
 
-                match direction {
-                    Direction::Up => {
-                        parent.copy_from(&image_to_append, 0, 0);
-                        parent.copy_from(&image, 0, image_to_append.height());
-                    }
-                    Direction::Down => {
-                        parent.copy_from(&image, 0, 0);
-                        parent.copy_from(&image_to_append, 0, image.height());
-                    }
-                    Direction::Left => {
-                        parent.copy_from(&image_to_append, 0, 0);
-                        parent.copy_from(&image, image_to_append.width(), 0);
-                    }
-                    Direction::Right => {
-                        parent.copy_from(&image, 0, 0);
-                        parent.copy_from(&image_to_append, image.width(), 0);
+                // Without an alignment the appended image inherits size of original image
+                if align.is_none() {
+                    image_to_append = if vertical {
+                        image_to_append.resize(image.width(), u32::MAX, algorithm)
+                    } else {
+                        image_to_append.resize(u32::MAX, image.height(), algorithm)
+                    };
+                }
+                let (w, h) = appended_dimensions(
+                    image.dimensions(),
+                    image_to_append.dimensions(),
+                    vertical,
+                    gutter,
+                )
+                .map_err(geometry)?;
+                let mut parent = ImageBuffer::from_pixel(w, h, rgba8(color));
+
+                // The first image starts at the top or left, the other one after it and the gutter
+                let (first, second) = match direction {
+                    Direction::Up | Direction::Left => (&image_to_append, &image),
+                    Direction::Down | Direction::Right => (&image, &image_to_append),
+                };
+                let align = align.as_ref().unwrap_or(&Align::Start);
+                for (i, part) in [first, second].iter().enumerate() {
+                    let along = if i == 0 {
+                        0
+                    } else if vertical {
+                        first.height() + gutter
+                    } else {
+                        first.width() + gutter
+                    };
+                    let (x, y) = if vertical {
+                        (align.offset(w, part.width()), along)
+                    } else {
+                        (along, align.offset(h, part.height()))
+                    };
+                    if !parent.copy_from(&part.to_rgba(), x, y) {
+                        return Err(geometry(format!(
+                            "append: {}x{} image doesn't fit at {},{} of the {}x{} result",
+                            part.width(),
+                            part.height(),
+                            x,
+                            y,
+                            w,
+                            h
+                        )));
                     }
                 }
-                image = parent;
+                image = ImageRgba8(parent);
+            }
+            Action::Overlay(ref filename, (x, y), opacity, ref mode, ref tile) => {
+                let top = if filename == infile {
+                    image.clone()
+                } else {
+                    images.get(filename)?.clone()
+                };
+                if let Some(ref tile) = *tile {
+                    image = composite::tile(image, &top, tile, opacity, mode);
+                    continue;
+                }
+                let free = (
+                    image.width().saturating_sub(top.width()),
+                    image.height().saturating_sub(top.height()),
+                );
+                image = composite::overlay(image, &top, resolve((x, y), free), opacity, mode)
+            }
+            Action::Grid(ref filenames, columns, spacing, color, ref caption) => {
+                let mut others = Vec::with_capacity(filenames.len());
+                for filename in filenames {
+                    others.push(if filename == infile {
+                        image.clone()
+                    } else {
+                        images.get(filename)?.clone()
+                    });
+                }
+                let mut cells = vec![(infile, &image)];
+                cells.extend(filenames.iter().map(String::as_str).zip(&others));
+
+                let layout = grid::Layout::new(
+                    cells.len() as u32,
+                    columns,
+                    spacing,
+                    image.dimensions(),
+                    caption
+                        .as_ref()
+                        .map(|(_, size)| size.resolve(image.dimensions())),
+                )
+                .map_err(geometry)?;
+                image = grid::draw(&cells, &layout, color, caption.as_ref(), filter)?
             }
-            Action::Format(f) => out_format = f,
+            Action::Pad(top, right, bottom, left, color) => {
+                let (left, top) = resolve((left, top), image.dimensions());
+                let (right, bottom) = resolve((right, bottom), image.dimensions());
+                let size = padded_dimensions(image.dimensions(), (left, top), (right, bottom))
+                    .map_err(geometry)?;
+                image = extend(&image, size, (left, top), color).map_err(geometry)?
+            }
+            Action::Extend(w, h, ref gravity, color) => {
+                let size = resolve((w, h), image.dimensions());
+                let position = gravity.offset(size, image.dimensions());
+                image = extend(&image, size, position, color).map_err(geometry)?
+            }
+            Action::Format(ref f) => out_format = f.clone(),
+            // Metadata is added once the image is encoded and the filter is known upfront
+            Action::Metadata(_) | Action::Filter(_) => {}
         };
     }
-    (image, out_format)
+    Ok((image, out_format))
+}
+
+/// Apply one of the lossless rotations or flips, which is also how images are turned upright
+/// according to their EXIF orientation when they're loaded
+pub(crate) fn rotate_or_flip(image: DynamicImage, action: &Action) -> DynamicImage {
+    match *action {
+        Action::Rotate(Direction::Right) => image.rotate90(),
+        Action::Rotate(Direction::Left) => image.rotate270(),
+        Action::Rotate(Direction::Down) => image.rotate180(),
+        Action::Flip(Orientation::Vertical) => image.flipv(),
+        Action::Flip(Orientation::Horizontal) => image.fliph(),
+        _ => image,
+    }
+}
+
+/// Predict the dimensions of `image` after `action` is applied without touching any pixels.
+/// Fails with a description of the problem if the action doesn't fit the image.
+pub fn dimensions_after(
+    action: &Action,
+    (w, h): (u32, u32),
+    infile: &str,
+    images: &mut Images,
+) -> Result<(u32, u32), String> {
+    let (w, h) = match *action {
+        Action::Crop(x, y, cw, ch) => {
+            let (x, y) = resolve((x, y), (w, h));
+            let (cw, ch) = resolve((cw, ch), (w, h));
+            if cw == 0 || ch == 0 {
+                return Err("crop: width and height must be greater than 0".to_owned());
+            }
+            if u64::from(x) + u64::from(cw) > u64::from(w)
+                || u64::from(y) + u64::from(ch) > u64::from(h)
+            {
+                return Err(format!(
+                    "crop: {},{},{},{} is outside of the {}x{} image",
+                    x, y, cw, ch, w, h
+                ));
+            }
+            (cw, ch)
+        }
+
+        Action::Rotate(Direction::Left) | Action::Rotate(Direction::Right) => (h, w),
+        Action::RotateBy(degrees, Canvas::Expand, _) => rotated_dimensions((w, h), degrees),
+
+        Action::Scale(nw, nh, _, ref mode) => match (resolve((nw, nh), (w, h)), mode) {
+            ((0, 0), _) => return Err("resize: width and height can't both be 0".to_owned()),
+            ((0, nh), _) => resize_dimensions((w, h), (u32::MAX, nh)),
+            ((nw, 0), _) => resize_dimensions((w, h), (nw, u32::MAX)),
+            ((nw, nh), ResizeMode::Fit) => resize_dimensions((w, h), (nw, nh)),
+            ((nw, nh), _) => (nw, nh),
+        },
+
+        Action::Append {
+            image: ref filename,
+            ref direction,
+            gutter,
+            ref align,
+            ..
+        } => {
+            let (aw, ah) = if filename == infile {
+                (w, h)
+            } else {
+                images.dimensions(filename).map_err(|e| e.to_string())?
+            };
+            let vertical = *direction == Direction::Up || *direction == Direction::Down;
+            let appended = match (align, vertical) {
+                (Some(_), _) => (aw, ah),
+                (None, true) => resize_dimensions((aw, ah), (w, u32::MAX)),
+                (None, false) => resize_dimensions((aw, ah), (u32::MAX, h)),
+            };
+            appended_dimensions((w, h), appended, vertical, gutter)?
+        }
+
+        Action::Overlay(ref filename, _, _, _, _) => {
+            if filename != infile {
+                images.dimensions(filename).map_err(|e| e.to_string())?;
+            }
+            (w, h)
+        }
+
+        Action::Pad(top, right, bottom, left, _) => {
+            let (left, top) = resolve((left, top), (w, h));
+            let (right, bottom) = resolve((right, bottom), (w, h));
+            padded_dimensions((w, h), (left, top), (right, bottom))?
+        }
+        Action::Extend(cw, ch, _, _) => {
+            let (cw, ch) = resolve((cw, ch), (w, h));
+            if cw < w || ch < h {
+                return Err(format!(
+                    "canvas: {}x{} is smaller than the {}x{} image",
+                    cw, ch, w, h
+                ));
+            }
+            (cw, ch)
+        }
+
+        Action::Grid(ref filenames, columns, spacing, _, ref caption) => {
+            for filename in filenames.iter().filter(|f| *f != infile) {
+                images.dimensions(filename).map_err(|e| e.to_string())?;
+            }
+            let layout = grid::Layout::new(
+                filenames.len() as u32 + 1,
+                columns,
+                spacing,
+                (w, h),
+                caption.as_ref().map(|(_, size)| size.resolve((w, h))),
+            )?;
+            layout.dimensions
+        }
+
+        _ => (w, h),
+    };
+    if w == 0 || h == 0 {
+        return Err(format!("would produce an empty {}x{} image", w, h));
+    }
+    Ok((w, h))
+}
+
+/// Place an image at a position on a canvas of `(w, h)` filled with a color
+fn extend(
+    image: &DynamicImage,
+    (w, h): (u32, u32),
+    (x, y): (u32, u32),
+    color: (f32, f32, f32, f32),
+) -> Result<DynamicImage, String> {
+    let mut canvas = ImageBuffer::from_pixel(w, h, rgba8(color));
+    if !canvas.copy_from(&image.to_rgba(), x, y) {
+        return Err(format!(
+            "{}x{} image doesn't fit at {},{} of a {}x{} canvas",
+            image.width(),
+            image.height(),
+            x,
+            y,
+            w,
+            h
+        ));
+    }
+    Ok(ImageRgba8(canvas))
+}
+
+/// The size of an image of `(w, h)` with margins added to every side
+fn padded_dimensions(
+    (w, h): (u32, u32),
+    (left, top): (u32, u32),
+    (right, bottom): (u32, u32),
+) -> Result<(u32, u32), String> {
+    let width = left.checked_add(w).and_then(|w| w.checked_add(right));
+    let height = top.checked_add(h).and_then(|h| h.checked_add(bottom));
+    match (width, height) {
+        (Some(width), Some(height)) => Ok((width, height)),
+        _ => Err(format!(
+            "pad: margins of {},{},{},{} are too large for the {}x{} image",
+            top, right, bottom, left, w, h
+        )),
+    }
+}
+
+/// The size of two images joined with a gutter between them
+fn appended_dimensions(
+    (w, h): (u32, u32),
+    (aw, ah): (u32, u32),
+    vertical: bool,
+    gutter: u32,
+) -> Result<(u32, u32), String> {
+    let joined = |a: u32, b: u32| a.checked_add(gutter).and_then(|a| a.checked_add(b));
+    let dimensions = if vertical {
+        joined(h, ah).map(|h| (w.max(aw), h))
+    } else {
+        joined(w, aw).map(|w| (w, h.max(ah)))
+    };
+    dimensions.ok_or_else(|| {
+        format!(
+            "append: {}x{} and {}x{} images with a gutter of {} are too large",
+            w, h, aw, ah, gutter
+        )
+    })
+}
+
+/// Resolve a pair of lengths against the width and height of an image
+fn resolve((x, y): (Length, Length), (w, h): (u32, u32)) -> (u32, u32) {
+    (x.resolve(w), y.resolve(h))
+}
+
+/// Convert a color with channels between 0 and 1 to 8 bit channels
+fn rgba8((r, g, b, a): (f32, f32, f32, f32)) -> Rgba<u8> {
+    Rgba {
+        data: [
+            (r * 255.0) as u8,
+            (g * 255.0) as u8,
+            (b * 255.0) as u8,
+            (a * 255.0) as u8,
+        ],
+    }
+}
+
+/// Rotate clockwise by `degrees`, using the exact 90 degree rotations whenever the angle allows it
+fn rotate_by(image: DynamicImage, degrees: f32, canvas: &Canvas, bg: Rgba<u8>) -> DynamicImage {
+    let degrees = degrees.rem_euclid(360.0);
+    match (degrees as u32, canvas) {
+        _ if degrees.fract() != 0.0 => {}
+        (0, _) => return image,
+        (180, _) => return image.rotate180(),
+        (90, Canvas::Expand) => return image.rotate90(),
+        (270, Canvas::Expand) => return image.rotate270(),
+        _ => {}
+    }
+
+    let (w, h) = match canvas {
+        Canvas::Expand => rotated_dimensions(image.dimensions(), degrees),
+        Canvas::Crop => image.dimensions(),
+    };
+    // Center the image on a canvas big enough to hold it both before and after rotating. The
+    // bounding box of a long and thin image turned by a small angle is narrower than the image.
+    let (pw, ph) = (w.max(image.width()), h.max(image.height()));
+    let mut padded = ImageBuffer::from_pixel(pw, ph, bg);
+    padded.copy_from(
+        &image.to_rgba(),
+        (pw - image.width()) / 2,
+        (ph - image.height()) / 2,
+    );
+    let mut rotated = ImageRgba8(affine::rotate_with_default(
+        &padded,
+        (pw as f32 / 2.0, ph as f32 / 2.0),
+        degrees.to_radians(),
+        bg,
+        Interpolation::Bilinear,
+    ));
+    if (pw, ph) == (w, h) {
+        return rotated;
+    }
+    rotated.crop((pw - w) / 2, (ph - h) / 2, w, h)
+}
+
+/// Resize to fit a box of `(w, h)` the way `mode` asks for
+fn scale(
+    image: DynamicImage,
+    (w, h): (u32, u32),
+    filter: FilterType,
+    mode: &ResizeMode,
+) -> DynamicImage {
+    match *mode {
+        ResizeMode::Fill => image.resize_exact(w, h, filter),
+        ResizeMode::Fit => image.resize(w, h, filter),
+        ResizeMode::Cover(ref gravity) => {
+            let (cw, ch) = cover_dimensions(image.dimensions(), (w, h));
+            let mut image = image.resize_exact(cw, ch, filter);
+            let (x, y) = gravity.offset((cw, ch), (w, h));
+            image.crop(x, y, w, h)
+        }
+        ResizeMode::Pad(ref gravity, color) => {
+            let image = image.resize(w, h, filter);
+            let mut padded = ImageBuffer::from_pixel(w, h, rgba8(color));
+            let (x, y) = gravity.offset((w, h), image.dimensions());
+            padded.copy_from(&image.to_rgba(), x, y);
+            ImageRgba8(padded)
+        }
+    }
+}
+
+/// The size of an image of `(w, h)` scaled as little as possible to cover a box of `(bw, bh)`
+fn cover_dimensions((w, h): (u32, u32), (bw, bh): (u32, u32)) -> (u32, u32) {
+    let (w, h, bw64, bh64) = (u64::from(w), u64::from(h), u64::from(bw), u64::from(bh));
+    if bw64 * h >= bh64 * w {
+        (bw, ((h * bw64 + w - 1) / w).max(bh64) as u32)
+    } else {
+        (((w * bh64 + h - 1) / h).max(bw64) as u32, bh)
+    }
+}
+
+/// The size of the smallest canvas holding an image of `(w, h)` rotated by `degrees`
+fn rotated_dimensions((w, h): (u32, u32), degrees: f32) -> (u32, u32) {
+    let degrees = degrees.rem_euclid(180.0);
+    if degrees == 0.0 {
+        return (w, h);
+    }
+    if degrees == 90.0 {
+        return (h, w);
+    }
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (sin, cos) = (sin.abs(), cos.abs());
+    let (w, h) = (w as f32, h as f32);
+    (
+        (w * cos + h * sin).round() as u32,
+        (w * sin + h * cos).round() as u32,
+    )
+}
+
+/// The size `DynamicImage::resize` produces when fitting `(w, h)` within `(nw, nh)`
+fn resize_dimensions((w, h): (u32, u32), (nw, nh): (u32, u32)) -> (u32, u32) {
+    let ratio = u64::from(w) * u64::from(nh);
+    let nratio = u64::from(nw) * u64::from(h);
+    if nratio <= ratio {
+        (nw, (u64::from(h) * u64::from(nw) / u64::from(w)) as u32)
+    } else {
+        ((u64::from(w) * u64::from(nh) / u64::from(h)) as u32, nh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Length::{FromEnd, Percent, Pixels};
+
+    const CLEAR: (f32, f32, f32, f32) = (0.0, 0.0, 0.0, 0.0);
+
+    /// Apply every action to an image of `(w, h)` one at a time and check that the predicted
+    /// dimensions match the image that comes out
+    fn check(start: (u32, u32), actions: &[Action]) {
+        let mut images = Images::new();
+        images.insert("other.png".to_owned(), DynamicImage::new_rgba8(30, 50));
+        let mut image = DynamicImage::new_rgba8(start.0, start.1);
+        for action in actions {
+            let predicted = dimensions_after(action, image.dimensions(), "in.png", &mut images)
+                .unwrap_or_else(|e| panic!("{:?} on {:?}: {}", action, image.dimensions(), e));
+            let before = image.dimensions();
+            image = apply_actions(
+                "in.png",
+                image,
+                ImageOutputFormat::PNG,
+                std::slice::from_ref(action),
+                &["argument 1".to_owned()],
+                &HashMap::new(),
+                &mut images,
+            )
+            .unwrap()
+            .0;
+            assert_eq!(
+                image.dimensions(),
+                predicted,
+                "{:?} on {:?}",
+                action,
+                before
+            );
+        }
+    }
+
+    #[test]
+    fn lengths() {
+        assert_eq!("20".parse(), Ok(Pixels(20)));
+        assert_eq!("20px".parse(), Ok(Pixels(20)));
+        assert_eq!("-20".parse(), Ok(FromEnd(20)));
+        assert_eq!("-20px".parse(), Ok(FromEnd(20)));
+        assert_eq!("50%".parse(), Ok(Percent(50.0)));
+        assert_eq!("12.5%".parse(), Ok(Percent(12.5)));
+        for invalid in &[
+            "", "px", "%", "-50%", "nan%", "inf%", "1.5", "1.5px", "--2", "20pt",
+        ] {
+            assert_eq!(invalid.parse::<Length>(), Err(()), "{}", invalid);
+        }
+        assert_eq!(Percent(50.0).resolve(333), 167);
+        assert_eq!(FromEnd(20).resolve(100), 80);
+        assert_eq!(FromEnd(200).resolve(100), 0);
+    }
+
+    #[test]
+    fn font_sizes() {
+        assert_eq!("1.5".parse(), Ok(FontSize::Relative(1.5)));
+        assert_eq!("24px".parse(), Ok(FontSize::Pixels(24.0)));
+        assert_eq!("12pt".parse(), Ok(FontSize::Points(12.0, 72.0)));
+        assert_eq!("5%w".parse(), Ok(FontSize::PercentWidth(5.0)));
+        assert_eq!("5%h".parse(), Ok(FontSize::PercentHeight(5.0)));
+        for invalid in &["", "0", "-5px", "0pt", "-1%w", "nan", "inf%h", "px", "5%"] {
+            assert_eq!(invalid.parse::<FontSize>(), Err(()), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn crop() {
+        check(
+            (333, 77),
+            &[
+                Action::Crop(Pixels(10), Pixels(5), Pixels(100), Pixels(50)),
+                Action::Crop(Percent(10.0), Percent(10.0), Percent(50.0), Percent(50.0)),
+                Action::Crop(Pixels(0), Pixels(0), FromEnd(3), FromEnd(1)),
+            ],
+        );
+    }
+
+    #[test]
+    fn resize() {
+        for &(w, h) in &[(333, 77), (77, 333), (100, 100), (20, 500)] {
+            let modes = [
+                ResizeMode::Fill,
+                ResizeMode::Fit,
+                ResizeMode::Cover(Gravity::Center),
+                ResizeMode::Cover(Gravity::SouthEast),
+                ResizeMode::Pad(Gravity::North, CLEAR),
+            ];
+            for mode in modes.iter() {
+                for &(nw, nh) in &[(100, 100), (50, 120), (121, 37), (100, 0), (0, 59)] {
+                    check(
+                        (w, h),
+                        &[Action::Scale(Pixels(nw), Pixels(nh), None, mode.clone())],
+                    );
+                }
+            }
+            check(
+                (w, h),
+                &[Action::Scale(
+                    Percent(33.0),
+                    Percent(150.0),
+                    None,
+                    ResizeMode::Fill,
+                )],
+            );
+        }
+    }
+
+    #[test]
+    fn append() {
+        for direction in [Direction::Left, Direction::Down].iter() {
+            for align in [None, Some(Align::Start), Some(Align::Center)].iter() {
+                check(
+                    (333, 77),
+                    &[
+                        Action::Append {
+                            image: "other.png".to_owned(),
+                            direction: direction.clone(),
+                            filter: None,
+                            gutter: 0,
+                            background: CLEAR,
+                            align: align.clone(),
+                        },
+                        Action::Append {
+                            image: "in.png".to_owned(),
+                            direction: direction.clone(),
+                            filter: None,
+                            gutter: 7,
+                            background: CLEAR,
+                            align: align.clone(),
+                        },
+                    ],
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pad_and_canvas() {
+        check(
+            (333, 77),
+            &[
+                Action::Pad(Pixels(1), Pixels(2), Pixels(3), Percent(10.0), CLEAR),
+                Action::Extend(Pixels(500), Percent(200.0), Gravity::SouthWest, CLEAR),
+                Action::Extend(FromEnd(0), FromEnd(0), Gravity::Center, CLEAR),
+            ],
+        );
+    }
+
+    #[test]
+    fn too_large() {
+        let mut images = Images::new();
+        images.insert("other.png".to_owned(), DynamicImage::new_rgba8(30, 50));
+        let max = Pixels(u32::MAX);
+        for action in &[
+            Action::Pad(Pixels(1), Pixels(0), Pixels(0), max, CLEAR),
+            Action::Pad(max, Pixels(0), Pixels(1), Pixels(0), CLEAR),
+            Action::Append {
+                image: "other.png".to_owned(),
+                direction: Direction::Left,
+                filter: None,
+                gutter: u32::MAX,
+                background: CLEAR,
+                align: None,
+            },
+            Action::Grid(vec!["other.png".to_owned()], 2, u32::MAX / 2, CLEAR, None),
+        ] {
+            assert!(
+                dimensions_after(action, (333, 77), "in.png", &mut images).is_err(),
+                "{:?}",
+                action
+            );
+        }
+    }
+
+    #[test]
+    fn rotate() {
+        for &(w, h) in &[(133, 77), (60, 90), (500, 1), (100, 1), (1, 300)] {
+            // Thin images turned a little have a bounding box that's narrower than they are
+            for &degrees in &[1.0, 10.0, 30.0, 45.0, 90.0, 135.5, 180.0, 270.0, -17.0] {
+                for canvas in [Canvas::Expand, Canvas::Crop].iter() {
+                    check((w, h), &[Action::RotateBy(degrees, canvas.clone(), CLEAR)]);
+                }
+            }
+            check(
+                (w, h),
+                &[
+                    Action::Rotate(Direction::Right),
+                    Action::Rotate(Direction::Down),
+                    Action::Rotate(Direction::Left),
+                ],
+            );
+        }
+    }
 }
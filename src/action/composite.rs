@@ -0,0 +1,204 @@
+use super::{rotate_by, Blend, Canvas, Tile};
+use image::{DynamicImage, GenericImageView, ImageRgba8, Rgba, RgbaImage};
+
+/// Draw `top` onto `image` with its top left corner at `(x, y)`, blending the colors with `mode`
+/// and fading it by `opacity`. Whatever is outside of `image` is cut off.
+pub fn overlay(
+    image: DynamicImage,
+    top: &DynamicImage,
+    (x, y): (u32, u32),
+    opacity: f32,
+    mode: &Blend,
+) -> DynamicImage {
+    let mut image = image.to_rgba();
+    let (w, h) = image.dimensions();
+    for (tx, ty, pixel) in top.pixels() {
+        let (px, py) = (u64::from(x) + u64::from(tx), u64::from(y) + u64::from(ty));
+        if px >= u64::from(w) || py >= u64::from(h) {
+            continue;
+        }
+        let bottom = image.get_pixel_mut(px as u32, py as u32);
+        *bottom = blend(*bottom, pixel, opacity, mode);
+    }
+    ImageRgba8(image)
+}
+
+/// Repeat `stamp` over the whole image in rows that are turned by the angle of the tile, with
+/// every other row shifted by half a copy. Transparent edges of the stamp are left out so the
+/// spacing is between what's visible.
+pub fn tile(
+    image: DynamicImage,
+    stamp: &DynamicImage,
+    tile: &Tile,
+    opacity: f32,
+    mode: &Blend,
+) -> DynamicImage {
+    let stamp = trim(stamp.to_rgba());
+    let (sw, sh) = stamp.dimensions();
+    if sw == 0 || sh == 0 {
+        return image;
+    }
+
+    // Fill a square as wide as the diagonal so the image is still covered once it's turned
+    let (w, h) = image.dimensions();
+    let side = (f64::from(w).hypot(f64::from(h))).ceil() as u32;
+    let (step_x, step_y) = (sw + tile.spacing, sh + tile.spacing);
+    let layer = RgbaImage::from_fn(side, side, |x, y| {
+        let shift = if (y / step_y) % 2 == 1 { step_x / 2 } else { 0 };
+        let (sx, sy) = ((x + shift) % step_x, y % step_y);
+        if sx < sw && sy < sh {
+            *stamp.get_pixel(sx, sy)
+        } else {
+            Rgba { data: [0, 0, 0, 0] }
+        }
+    });
+    let transparent = Rgba { data: [0, 0, 0, 0] };
+    let layer = rotate_by(ImageRgba8(layer), tile.angle, &Canvas::Crop, transparent).crop(
+        (side - w) / 2,
+        (side - h) / 2,
+        w,
+        h,
+    );
+    overlay(image, &layer, (0, 0), opacity * tile.opacity, mode)
+}
+
+/// Cut off the rows and columns around an image that are fully transparent
+fn trim(image: RgbaImage) -> RgbaImage {
+    let visible = image
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel.data[3] > 0);
+    let (x0, y0, x1, y1) = visible
+        .fold((u32::MAX, u32::MAX, 0, 0), |(x0, y0, x1, y1), (x, y, _)| {
+            (x0.min(x), y0.min(y), x1.max(x + 1), y1.max(y + 1))
+        });
+    if x1 == 0 {
+        return RgbaImage::new(0, 0);
+    }
+    RgbaImage::from_fn(x1 - x0, y1 - y0, |x, y| *image.get_pixel(x0 + x, y0 + y))
+}
+
+/// Composite one pixel over another. The blend mode only decides the color where both pixels are
+/// opaque, elsewhere it fades towards plain alpha compositing.
+pub(super) fn blend(bottom: Rgba<u8>, top: Rgba<u8>, opacity: f32, mode: &Blend) -> Rgba<u8> {
+    let channel = |c: u8| f32::from(c) / 255.0;
+    let ta = channel(top.data[3]) * opacity.max(0.0).min(1.0);
+    let ba = channel(bottom.data[3]);
+    let alpha = ta + ba * (1.0 - ta);
+    if alpha <= 0.0 {
+        return Rgba { data: [0, 0, 0, 0] };
+    }
+
+    let mut data = [0; 4];
+    for (i, c) in data.iter_mut().enumerate() {
+        *c = if i == 3 {
+            (alpha * 255.0).round() as u8
+        } else {
+            let (b, t) = (channel(bottom.data[i]), channel(top.data[i]));
+            let mixed = (1.0 - ba) * t + ba * mix(b, t, mode);
+            let color = (ta * mixed + (1.0 - ta) * ba * b) / alpha;
+            (color * 255.0).round() as u8
+        };
+    }
+    Rgba { data }
+}
+
+fn mix(b: f32, t: f32, mode: &Blend) -> f32 {
+    match *mode {
+        Blend::Normal => t,
+        Blend::Multiply => b * t,
+        Blend::Screen => 1.0 - (1.0 - b) * (1.0 - t),
+        Blend::Overlay if b < 0.5 => 2.0 * b * t,
+        Blend::Overlay => 1.0 - 2.0 * (1.0 - b) * (1.0 - t),
+        Blend::Darken => b.min(t),
+        Blend::Lighten => b.max(t),
+        Blend::Difference => (b - t).abs(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [Blend; 7] = [
+        Blend::Normal,
+        Blend::Multiply,
+        Blend::Screen,
+        Blend::Overlay,
+        Blend::Darken,
+        Blend::Lighten,
+        Blend::Difference,
+    ];
+
+    fn rgba(r: u8, g: u8, b: u8, a: u8) -> Rgba<u8> {
+        Rgba { data: [r, g, b, a] }
+    }
+
+    #[test]
+    fn modes() {
+        let (bottom, top) = (rgba(200, 100, 50, 255), rgba(100, 200, 150, 255));
+        let expected = [
+            rgba(100, 200, 150, 255),
+            rgba(78, 78, 29, 255),
+            rgba(222, 222, 171, 255),
+            rgba(188, 157, 59, 255),
+            rgba(100, 100, 50, 255),
+            rgba(200, 200, 150, 255),
+            rgba(100, 100, 100, 255),
+        ];
+        for (mode, expected) in MODES.iter().zip(expected.iter()) {
+            assert_eq!(blend(bottom, top, 1.0, mode), *expected, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn opacity() {
+        let (bottom, top) = (rgba(200, 100, 50, 255), rgba(100, 200, 150, 255));
+        for mode in MODES.iter() {
+            assert_eq!(blend(bottom, top, 0.0, mode), bottom, "{:?}", mode);
+            assert_eq!(blend(bottom, top, -1.0, mode), bottom, "{:?}", mode);
+            assert_eq!(
+                blend(bottom, top, 2.0, mode),
+                blend(bottom, top, 1.0, mode),
+                "{:?}",
+                mode
+            );
+        }
+        assert_eq!(
+            blend(bottom, top, 0.5, &Blend::Normal),
+            rgba(150, 150, 100, 255)
+        );
+    }
+
+    #[test]
+    fn transparency() {
+        let top = rgba(100, 200, 150, 128);
+        for mode in MODES.iter() {
+            // Nothing below means there's nothing to blend with
+            assert_eq!(blend(rgba(0, 0, 0, 0), top, 1.0, mode), top, "{:?}", mode);
+            assert_eq!(
+                blend(rgba(9, 9, 9, 0), rgba(1, 2, 3, 0), 1.0, mode),
+                rgba(0, 0, 0, 0),
+                "{:?}",
+                mode
+            );
+            // A transparent top leaves the bottom as it is
+            let bottom = rgba(200, 100, 50, 77);
+            assert_eq!(
+                blend(bottom, rgba(1, 2, 3, 0), 1.0, mode),
+                bottom,
+                "{:?}",
+                mode
+            );
+        }
+        // Half transparent on top of opaque is opaque
+        assert_eq!(
+            blend(
+                rgba(0, 0, 0, 255),
+                rgba(255, 255, 255, 51),
+                1.0,
+                &Blend::Normal
+            ),
+            rgba(51, 51, 51, 255)
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! Finding fonts by path or family name and keeping them loaded.
+
+use crate::error::ImageneError;
+use lazy_static::lazy_static;
+use rusttype::{Font, FontCollection};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// DejaVu Sans, used when no font is named so text works without any font installed
+const BUNDLED: &[u8] = include_bytes!("../../fonts/DejaVuSans.ttf");
+
+lazy_static! {
+    /// Fonts that were used already, by the name they were loaded with
+    static ref LOADED: Mutex<HashMap<String, Font<'static>>> = Mutex::new(HashMap::new());
+    /// Every font in the system font directories, scanned the first time a family is looked up
+    static ref SYSTEM: HashMap<String, (PathBuf, usize)> = scan();
+}
+
+/// Load the fonts of a fallback chain such as `Noto Sans CJK JP|DejaVu Sans`, where each glyph
+/// comes from the first font that has it
+pub fn load_chain(names: &str) -> Result<Vec<Font<'static>>, ImageneError> {
+    names.split('|').map(load).collect()
+}
+
+/// Load a font by path or by family name, like `DejaVu Sans` or `DejaVu Sans Bold`, from the
+/// system font directories. An empty name is the bundled font. A `#index` suffix picks a face
+/// of a collection such as `fonts.ttc#2`. Fonts stay loaded once used.
+pub fn load(name: &str) -> Result<Font<'static>, ImageneError> {
+    if let Some(font) = LOADED.lock().unwrap().get(name) {
+        return Ok(font.clone());
+    }
+
+    let err = |reason: String| ImageneError::Font(name.to_owned(), reason);
+    let (file, index) = match name.rfind('#') {
+        Some(i) if name[i + 1..].parse::<usize>().is_ok() => {
+            (&name[..i], name[i + 1..].parse::<usize>().ok())
+        }
+        _ => (name, None),
+    };
+    let font = if file.is_empty() {
+        Font::from_bytes(BUNDLED).map_err(|e| err(e.to_string()))?
+    } else {
+        let (path, face) = if Path::new(file).is_file() {
+            (PathBuf::from(file), 0)
+        } else {
+            find(file).ok_or_else(|| {
+                err("no such file or font family in the system font directories".to_owned())
+            })?
+        };
+        let bytes = fs::read(&path).map_err(|e| err(e.to_string()))?;
+        FontCollection::from_bytes(bytes)
+            .and_then(|collection| collection.font_at(index.unwrap_or(face)))
+            .map_err(|e| err(e.to_string()))?
+    };
+    LOADED.lock().unwrap().insert(name.to_owned(), font.clone());
+    Ok(font)
+}
+
+/// Find the file and face index of a font by its name, ignoring case, spaces and dashes. Italic
+/// and oblique are interchangeable since families tend to only have one of them.
+fn find(name: &str) -> Option<(PathBuf, usize)> {
+    let name = normalize(name);
+    SYSTEM
+        .get(&name)
+        .or_else(|| SYSTEM.get(&name.replace("italic", "oblique")))
+        .or_else(|| SYSTEM.get(&name.replace("oblique", "italic")))
+        .cloned()
+}
+
+/// Directories fontconfig and the other platforms keep fonts in
+fn font_dirs() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = [
+        "/usr/share/fonts",
+        "/usr/local/share/fonts",
+        "/Library/Fonts",
+        "/System/Library/Fonts",
+    ]
+    .iter()
+    .map(PathBuf::from)
+    .collect();
+    if let Some(home) = env::var_os("HOME").map(PathBuf::from) {
+        dirs.push(home.join(".fonts"));
+        dirs.push(home.join("Library/Fonts"));
+        match env::var_os("XDG_DATA_HOME") {
+            Some(data) => dirs.push(PathBuf::from(data).join("fonts")),
+            None => dirs.push(home.join(".local/share/fonts")),
+        }
+    }
+    if let Some(windows) = env::var_os("WINDIR") {
+        dirs.push(PathBuf::from(windows).join("Fonts"));
+    }
+    dirs
+}
+
+/// Index every font face in the font directories by its names. The path that sorts first wins
+/// when names clash.
+fn scan() -> HashMap<String, (PathBuf, usize)> {
+    let mut fonts = HashMap::new();
+    for path in font_files(font_dirs()) {
+        let faces = File::open(&path).and_then(|mut f| face_names(&mut f));
+        for (face, names) in faces.into_iter().flatten().enumerate() {
+            for name in names {
+                fonts.entry(name).or_insert_with(|| (path.clone(), face));
+            }
+        }
+    }
+    fonts
+}
+
+/// Every font file in the directories and their subdirectories, sorted by path
+fn font_files(mut dirs: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut visited = HashSet::new();
+    while let Some(dir) = dirs.pop() {
+        // Symlinks can lead back to a directory that was already read, even to a parent of it
+        match fs::canonicalize(&dir) {
+            Ok(canonical) if !visited.contains(&canonical) => visited.insert(canonical),
+            _ => continue,
+        };
+        for entry in fs::read_dir(&dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok())
+        {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+                if ["ttf", "otf", "ttc", "otc"].contains(&ext.to_lowercase().as_str()) {
+                    files.push(path);
+                }
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// The normalized names every face of a font file can be found by, without reading more of the
+/// file than the name tables
+fn face_names<R: Read + Seek>(r: &mut R) -> std::io::Result<Vec<Vec<String>>> {
+    let header = read_at(r, 0, 12)?;
+    let offsets = if &header[0..4] == b"ttcf" {
+        let count = be_u32(&header[8..]) as usize;
+        let offsets = read_at(r, 12, count.min(256) * 4)?;
+        offsets.chunks(4).map(|o| u64::from(be_u32(o))).collect()
+    } else {
+        vec![0]
+    };
+
+    let mut faces = Vec::new();
+    for offset in offsets {
+        let count = usize::from(be_u16(&read_at(r, offset + 4, 2)?));
+        let tables = read_at(r, offset + 12, count * 16)?;
+        let names = tables
+            .chunks(16)
+            .find(|table| &table[0..4] == b"name")
+            .map(|table| {
+                read_at(
+                    r,
+                    u64::from(be_u32(&table[8..])),
+                    be_u32(&table[12..]) as usize,
+                )
+            })
+            .transpose()?
+            .map(|table| names(&table))
+            .unwrap_or_default();
+        faces.push(names);
+    }
+    Ok(faces)
+}
+
+/// Read the full name, family and style from a name table and combine them into lookup names
+fn names(table: &[u8]) -> Vec<String> {
+    let mut found: HashMap<u16, String> = HashMap::new();
+    let count = table.get(2..4).map(be_u16).unwrap_or(0);
+    let strings = table.get(4..6).map(be_u16).unwrap_or(0) as usize;
+    for record in table
+        .get(6..)
+        .unwrap_or(&[])
+        .chunks(12)
+        .take(usize::from(count))
+    {
+        if record.len() < 12 {
+            break;
+        }
+        let (platform, language, id) = (be_u16(record), be_u16(&record[4..]), be_u16(&record[6..]));
+        let (length, offset) = (
+            be_u16(&record[8..]) as usize,
+            be_u16(&record[10..]) as usize,
+        );
+        let bytes = match table.get(strings + offset..strings + offset + length) {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        let text = match platform {
+            // Unicode and Windows names are UTF-16, Macintosh ones are close enough to ASCII
+            0 | 3 => {
+                String::from_utf16_lossy(&bytes.chunks_exact(2).map(be_u16).collect::<Vec<u16>>())
+            }
+            1 => bytes.iter().map(|b| char::from(*b)).collect(),
+            _ => continue,
+        };
+        // Prefer English names when a font has several languages
+        if !found.contains_key(&id) || (platform == 3 && language == 0x409) {
+            found.insert(id, text);
+        }
+    }
+
+    let mut names = Vec::new();
+    let get = |id: u16| found.get(&id).map(String::as_str);
+    if let Some(full) = get(4) {
+        names.push(normalize(full));
+    }
+    for (family, style) in [(get(1), get(2)), (get(16), get(17))].iter() {
+        if let (Some(family), Some(style)) = (family, style) {
+            names.push(normalize(&format!("{}{}", family, style)));
+            if ["regular", "book", "normal", "roman"].contains(&normalize(style).as_str()) {
+                names.push(normalize(family));
+            }
+        }
+    }
+    names
+}
+
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn read_at<R: Read + Seek>(r: &mut R, offset: u64, length: usize) -> std::io::Result<Vec<u8>> {
+    let mut bytes = vec![0; length];
+    r.seek(SeekFrom::Start(offset))?;
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn be_u16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+fn be_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn symlink_cycles() {
+        let root = env::temp_dir().join(format!("imagene-fonts-{}", std::process::id()));
+        let fonts = root.join("fonts");
+        fs::create_dir_all(&fonts).unwrap();
+        fs::write(fonts.join("a.ttf"), b"").unwrap();
+        fs::write(fonts.join("b.TTC"), b"").unwrap();
+        fs::write(fonts.join("readme.txt"), b"").unwrap();
+        symlink(&root, fonts.join("loop")).unwrap();
+
+        let files = font_files(vec![root.clone(), fonts.clone()]);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(files, [fonts.join("a.ttf"), fonts.join("b.TTC")]);
+    }
+}
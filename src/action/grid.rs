@@ -0,0 +1,114 @@
+use super::{rgba8, text, FontSize, Gravity, TextEffects, TextLayout};
+use crate::error::ImageneError;
+use image::{DynamicImage, FilterType, GenericImage, GenericImageView, ImageBuffer, ImageRgba8};
+use std::path::Path;
+
+/// How the cells of a contact sheet are laid out
+pub struct Layout {
+    pub columns: u32,
+    /// Size of a cell without its caption
+    pub cell: (u32, u32),
+    pub caption_height: u32,
+    pub spacing: u32,
+    /// Size of the whole sheet
+    pub dimensions: (u32, u32),
+}
+
+impl Layout {
+    /// Lay out `count` cells the size of the first image, `(w, h)`, in at most `columns` columns
+    /// with room for captions in a font size of `caption` pixels. Fails if the sheet would be too
+    /// large.
+    pub fn new(
+        count: u32,
+        columns: u32,
+        spacing: u32,
+        (w, h): (u32, u32),
+        caption: Option<f32>,
+    ) -> Result<Self, String> {
+        let columns = columns.max(1).min(count);
+        let rows = (count + columns - 1) / columns;
+        // Leave room for a line of text and a little space around it
+        let caption_height = caption.map(|size| (size * 1.25).ceil() as u32).unwrap_or(0);
+
+        let side = |count: u32, cell: Option<u32>| {
+            count
+                .checked_mul(cell?)?
+                .checked_add((count + 1).checked_mul(spacing)?)
+        };
+        let too_large = || {
+            format!(
+                "grid: {} columns and {} rows of {}x{} cells are too large",
+                columns, rows, w, h
+            )
+        };
+        let dimensions = (
+            side(columns, Some(w)).ok_or_else(too_large)?,
+            side(rows, h.checked_add(caption_height)).ok_or_else(too_large)?,
+        );
+        Ok(Layout {
+            columns,
+            cell: (w, h),
+            caption_height,
+            spacing,
+            dimensions,
+        })
+    }
+
+    /// Top left corner of the cell at `index`
+    fn position(&self, index: u32) -> (u32, u32) {
+        let (w, h) = self.cell;
+        let (column, row) = (index % self.columns, index / self.columns);
+        (
+            self.spacing + column * (w + self.spacing),
+            self.spacing + row * (h + self.caption_height + self.spacing),
+        )
+    }
+}
+
+/// Scale every image to fit into a cell and lay them out row by row on a background, with the
+/// name of each image below it if there's a `(font, size)` for captions
+pub fn draw(
+    cells: &[(&str, &DynamicImage)],
+    layout: &Layout,
+    background: (f32, f32, f32, f32),
+    caption: Option<&(String, FontSize)>,
+    filter: FilterType,
+) -> Result<DynamicImage, ImageneError> {
+    let (w, h) = layout.dimensions;
+    let mut sheet = ImageBuffer::from_pixel(w, h, rgba8(background));
+    // Captions are dark on light backgrounds and light on dark ones
+    let (r, g, b, _) = background;
+    let ink = if r * 0.3 + g * 0.59 + b * 0.11 > 0.5 {
+        (0, 0, 0, 255)
+    } else {
+        (255, 255, 255, 255)
+    };
+
+    for (i, (name, image)) in cells.iter().enumerate() {
+        let (x, y) = layout.position(i as u32);
+        let image = image.resize(layout.cell.0, layout.cell.1, filter);
+        let (dx, dy) = Gravity::Center.offset(layout.cell, image.dimensions());
+        sheet.copy_from(&image.to_rgba(), x + dx, y + dy);
+
+        if let Some((font, size)) = caption {
+            let label = Path::new(name)
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| (*name).to_owned());
+            let strip =
+                ImageBuffer::from_pixel(layout.cell.0, layout.caption_height, rgba8(background));
+            let strip = text::draw(
+                ImageRgba8(strip),
+                ink,
+                // Sized by the cell rather than the strip it's drawn on
+                (font, FontSize::Pixels(size.resolve(layout.cell))),
+                (0, 0),
+                &label,
+                &TextLayout::default(),
+                &TextEffects::default(),
+            )?;
+            sheet.copy_from(&strip.to_rgba(), x, y + layout.cell.1);
+        }
+    }
+    Ok(ImageRgba8(sheet))
+}
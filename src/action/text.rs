@@ -2,57 +2,295 @@ extern crate image;
 extern crate imageproc;
 extern crate rusttype;
 
-use image::{DynamicImage, GenericImageView, ImageRgba8};
-use rusttype::FontCollection;
-
-use std::fs;
+use super::{composite, font, rgba8, Blend, FontSize, TextEffects, TextLayout};
+use crate::error::ImageneError;
+use crate::exif;
+use crate::pipeline::Images;
+use image::{DynamicImage, GenericImageView, GrayImage, ImageRgba8, Rgba, RgbaImage};
+use rusttype::{Font, GlyphId, Point, PositionedGlyph, Scale};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Draw text at `(x, y)`, where the anchor of the layout decides which point of the text goes
+/// there. Lines are broken at `\n` and wrapped at word boundaries to stay within the maximum
+/// width. Glyphs are composited over the image, so the alpha of the color and the anti-aliased
+/// edges blend with what's below instead of replacing it. The background box, shadow and outline
+/// of the effects are drawn below the text in that order. `font` can be a fallback chain, each
+/// glyph is taken from the first font that has it.
 pub fn draw(
-    mut image: DynamicImage, // Taking ownership is fine since it returns it back
+    image: DynamicImage, // Taking ownership is fine since it returns it back
     rgba: (u8, u8, u8, u8),
-    font: &str,
-    (x, y): (f32, f32),
-    scale: f32,
+    (font, size): (&str, FontSize),
+    (x, y): (u32, u32),
     text: &str,
-) -> DynamicImage {
+    layout: &TextLayout,
+    effects: &TextEffects,
+) -> Result<DynamicImage, ImageneError> {
     let color = image::Rgba {
         data: [rgba.0, rgba.1, rgba.2, rgba.3],
     };
-    let font = match load_font(&font) {
-        Err(e) => {
-            eprintln!("{:?}", e);
-            return image;
+    let fonts = font::load_chain(font)?;
+    let scale = rusttype::Scale::uniform(size.resolve(image.dimensions()));
+    // Lines are spaced by the first font, fallbacks only fill in glyphs
+    let v_metrics = fonts[0].v_metrics(scale);
+    let line_height = (v_metrics.ascent - v_metrics.descent + v_metrics.line_gap) * layout.spacing;
+
+    let max_width = layout
+        .max_width
+        .map(|width| width.resolve(image.width()) as f32);
+    let lines: Vec<(String, f32)> = text
+        .split('\n')
+        .flat_map(|paragraph| wrap(&fonts, scale, paragraph, max_width))
+        .map(|line| {
+            let width = line_width(&fonts, scale, &line);
+            (line, width)
+        })
+        .collect();
+
+    // The anchor is a point on the box around every line
+    let width = lines.iter().map(|(_, w)| *w).fold(0.0, f32::max);
+    let height = (lines.len() as f32 - 1.0) * line_height + v_metrics.ascent - v_metrics.descent;
+    let (ax, ay) = layout
+        .anchor
+        .offset((width.ceil() as u32, height.ceil() as u32), (0, 0));
+    let (left, top) = (x as f32 - ax as f32, y as f32 - ay as f32);
+
+    // Everything is drawn from the coverage of the glyphs so effects follow their shape
+    let (w, h) = image.dimensions();
+    let mut mask = GrayImage::new(w, h);
+    for (i, (line, line_width)) in lines.iter().enumerate() {
+        let indent = layout
+            .align
+            .offset(width.ceil() as u32, line_width.ceil() as u32);
+        let start = rusttype::point(
+            left + indent as f32,
+            top + i as f32 * line_height + v_metrics.ascent,
+        );
+        for glyph in layout_line(&fonts, scale, line, start) {
+            let bounds = match glyph.pixel_bounding_box() {
+                Some(bounds) => bounds,
+                None => continue,
+            };
+            glyph.draw(|gx, gy, coverage| {
+                let (px, py) = (bounds.min.x + gx as i32, bounds.min.y + gy as i32);
+                if px >= 0 && py >= 0 && px < w as i32 && py < h as i32 {
+                    let pixel = mask.get_pixel_mut(px as u32, py as u32);
+                    pixel.data[0] = pixel.data[0].max((coverage * 255.0) as u8);
+                }
+            });
         }
-        Ok(f) => f,
+    }
+
+    let mut image = image.to_rgba();
+    if let Some((padding, background)) = effects.background {
+        let padding = padding as f32;
+        let (x0, y0) = ((left - padding).max(0.0), (top - padding).max(0.0));
+        let (x1, y1) = (
+            (left + width + padding).min(w as f32),
+            (top + height + padding).min(h as f32),
+        );
+        let background = rgba8(background);
+        for y in y0 as u32..y1.ceil() as u32 {
+            for x in x0 as u32..x1.ceil() as u32 {
+                let pixel = image.get_pixel_mut(x, y);
+                *pixel = composite::blend(*pixel, background, 1.0, &Blend::Normal);
+            }
+        }
+    }
+    if let Some(ref shadow) = effects.shadow {
+        let (dx, dy) = shadow.offset;
+        let mut offset = GrayImage::new(w, h);
+        for (x, y, pixel) in mask.enumerate_pixels() {
+            let (sx, sy) = (x as i64 + i64::from(dx), y as i64 + i64::from(dy));
+            if pixel.data[0] > 0 && sx >= 0 && sy >= 0 && sx < w.into() && sy < h.into() {
+                offset.put_pixel(sx as u32, sy as u32, *pixel);
+            }
+        }
+        if shadow.blur > 0.0 {
+            offset = imageproc::filter::gaussian_blur_f32(&offset, shadow.blur);
+        }
+        paint(&mut image, &offset, rgba8(shadow.color));
+    }
+    if let Some((outline, stroke)) = effects.outline {
+        paint(&mut image, &dilate(&mask, outline), rgba8(stroke));
+    }
+    paint(&mut image, &mask, color);
+    Ok(ImageRgba8(image))
+}
+
+/// Fill in `{filename}`, `{width}`, `{height}`, `{date}` and `{exif.Tag}` placeholders for the
+/// image `name` as it is at this step. EXIF tags the image doesn't have are left empty and
+/// anything else in braces is kept as is.
+pub fn expand(text: &str, name: &str, (w, h): (u32, u32), images: &mut Images) -> String {
+    let filename = Path::new(name)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut text = text
+        .replace("{filename}", &filename)
+        .replace("{width}", &w.to_string())
+        .replace("{height}", &h.to_string());
+    if text.contains("{date}") {
+        text = text.replace("{date}", &today());
+    }
+
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text.as_str();
+    while let Some(start) = rest.find("{exif.") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let tag = &rest[start + "{exif.".len()..end];
+        expanded.push_str(&rest[..start]);
+        if let Some(value) = images.exif(name).and_then(|exif| exif::field(exif, tag)) {
+            expanded.push_str(&value);
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// The current date in UTC as `YYYY-MM-DD`
+fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86_400)
+        .unwrap_or(0) as i64;
+    date(days)
+}
+
+/// The date `days` after the first of January 1970 as `YYYY-MM-DD`
+fn date(days: i64) -> String {
+    // Count in 400 year eras starting on the first of March, which puts leap days at the end
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Composite a color over the image wherever the mask covers it
+fn paint(image: &mut RgbaImage, mask: &GrayImage, color: Rgba<u8>) {
+    for (pixel, coverage) in image.pixels_mut().zip(mask.pixels()) {
+        if coverage.data[0] > 0 {
+            let coverage = f32::from(coverage.data[0]) / 255.0;
+            *pixel = composite::blend(*pixel, color, coverage, &Blend::Normal);
+        }
+    }
+}
+
+/// Grow the covered area of a mask by `radius` pixels in every direction
+fn dilate(mask: &GrayImage, radius: u32) -> GrayImage {
+    let (w, h) = mask.dimensions();
+    let r = radius as i64;
+    let mut grown = mask.clone();
+    for (x, y, pixel) in mask.enumerate_pixels() {
+        if pixel.data[0] == 0 {
+            continue;
+        }
+        for dy in -r..=r {
+            for dx in -r..=r {
+                let (gx, gy) = (x as i64 + dx, y as i64 + dy);
+                if dx * dx + dy * dy > r * r || gx < 0 || gy < 0 || gx >= w.into() || gy >= h.into()
+                {
+                    continue;
+                }
+                let target = grown.get_pixel_mut(gx as u32, gy as u32);
+                target.data[0] = target.data[0].max(pixel.data[0]);
+            }
+        }
+    }
+    grown
+}
+
+/// Split a paragraph into lines at spaces so every line fits into `max_width`. Words that are
+/// wider on their own get a line to themselves.
+fn wrap(fonts: &[Font<'_>], scale: Scale, paragraph: &str, max_width: Option<f32>) -> Vec<String> {
+    let max_width = match max_width {
+        Some(max_width) => max_width,
+        None => return vec![paragraph.to_owned()],
     };
-    let (w, h) = (image.width() as f32, image.height() as f32);
-    ImageRgba8(imageproc::drawing::draw_text(
-        &mut image,
-        color,
-        (w * x) as u32,
-        (h * y) as u32,
-        rusttype::Scale::uniform(w as f32 * (scale * 0.1)),
-        &font,
-        text,
-    ))
-}
-
-fn load_font(name: &str) -> Result<rusttype::Font, ()> {
-    let bytes = fs::read(name).map_err(|e| {
-        eprintln!("loading {}: {}", name, e);
-    });
-    if bytes.is_err() {
-        return Err(());
-    }
-
-    Ok(FontCollection::from_bytes(bytes.unwrap())
-        .map_err(|e| {
-            eprintln!("{}", e);
-        })
-        .unwrap()
-        .into_font()
-        .map_err(|e| {
-            eprintln!("{}", e);
-        })
-        .unwrap())
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in paragraph.split(' ') {
+        let candidate = if line.is_empty() {
+            word.to_owned()
+        } else {
+            format!("{} {}", line, word)
+        };
+        if !line.is_empty() && line_width(fonts, scale, &candidate) > max_width {
+            lines.push(line);
+            line = word.to_owned();
+        } else {
+            line = candidate;
+        }
+    }
+    lines.push(line);
+    lines
+}
+
+/// How far a line of text advances
+fn line_width(fonts: &[Font<'_>], scale: Scale, line: &str) -> f32 {
+    layout_line(fonts, scale, line, rusttype::point(0.0, 0.0))
+        .last()
+        .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.0)
+}
+
+/// Position the glyphs of a line starting at `start`, taking each from the first font that has
+/// it. Glyphs no font has come out as the missing glyph of the first font. Kerning only applies
+/// between glyphs of the same font.
+fn layout_line<'a>(
+    fonts: &'a [Font<'_>],
+    scale: Scale,
+    line: &str,
+    start: Point<f32>,
+) -> Vec<PositionedGlyph<'a>> {
+    let mut glyphs = Vec::new();
+    let mut caret = start.x;
+    let mut previous: Option<(usize, GlyphId)> = None;
+    for c in line.chars() {
+        if c.is_control() {
+            continue;
+        }
+        let index = fonts
+            .iter()
+            .position(|font| font.glyph(c).id() != GlyphId(0))
+            .unwrap_or(0);
+        let glyph = fonts[index].glyph(c).scaled(scale);
+        if let Some((previous_index, previous_id)) = previous {
+            if previous_index == index {
+                caret += fonts[index].pair_kerning(scale, previous_id, glyph.id());
+            }
+        }
+        previous = Some((index, glyph.id()));
+        let advance = glyph.h_metrics().advance_width;
+        glyphs.push(glyph.positioned(rusttype::point(caret, start.y)));
+        caret += advance;
+    }
+    glyphs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::date;
+
+    #[test]
+    fn dates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(-1), "1969-12-31");
+        assert_eq!(date(11_016), "2000-02-29");
+        assert_eq!(date(11_017), "2000-03-01");
+        assert_eq!(date(19_723), "2024-01-01");
+        assert_eq!(date(47_540), "2100-02-28");
+        assert_eq!(date(47_541), "2100-03-01");
+    }
 }
@@ -0,0 +1,285 @@
+//! Applying one pipeline to many input files.
+
+use crate::action::Flag;
+use crate::cli;
+use crate::error::ImageneError;
+use crate::pipeline::{self, Images, Pipeline};
+use image::DynamicImage;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// Expand a glob pattern supporting `*`, `?` and `[...]` into the sorted list of matching files.
+/// A pattern without any wildcards is a single file.
+pub fn expand(pattern: &str) -> Result<Vec<String>, ImageneError> {
+    let mut paths = vec![if pattern.starts_with('/') {
+        String::from("/")
+    } else {
+        String::new()
+    }];
+
+    for component in pattern.split('/').filter(|c| !c.is_empty()) {
+        let mut next = Vec::new();
+        for path in paths {
+            if !component.contains(&['*', '?', '['][..]) {
+                next.push(format!("{}{}", path, component));
+                continue;
+            }
+            let dir = if path.is_empty() { "." } else { &path };
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            let pattern: Vec<char> = component.chars().collect();
+            for entry in entries.filter_map(|e| e.ok()) {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                // Like in a shell, hidden files have to be matched explicitly
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                if matches(&pattern, &name.chars().collect::<Vec<char>>()) {
+                    next.push(format!("{}{}", path, name));
+                }
+            }
+        }
+        paths = next.into_iter().map(|p| p + "/").collect();
+    }
+
+    let mut files: Vec<String> = paths
+        .into_iter()
+        .map(|p| p.trim_end_matches('/').to_owned())
+        .filter(|p| Path::new(p).is_file())
+        .collect();
+    if files.is_empty() {
+        return Err(ImageneError::MissingInput(pattern.to_owned()));
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|i| matches(&pattern[1..], &name[i..])),
+        Some('?') => !name.is_empty() && matches(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let end = match pattern.iter().skip(1).position(|c| *c == ']') {
+                Some(end) => end + 1,
+                // An unclosed bracket is matched literally
+                None => return name.first() == Some(&'[') && matches(&pattern[1..], &name[1..]),
+            };
+            let (negated, set) = match pattern[1] {
+                '!' | '^' => (true, &pattern[2..end]),
+                _ => (false, &pattern[1..end]),
+            };
+            match name.first() {
+                Some(c) => in_set(set, *c) != negated && matches(&pattern[end + 1..], &name[1..]),
+                None => false,
+            }
+        }
+        Some(c) => name.first() == Some(c) && matches(&pattern[1..], &name[1..]),
+    }
+}
+
+fn in_set(set: &[char], c: char) -> bool {
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            if set[i] <= c && c <= set[i + 2] {
+                return true;
+            }
+            i += 3;
+        } else {
+            if set[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+/// Fill in `{stem}`, `{ext}`, `{name}` and `{dir}` of an output template for an input file
+pub fn output_name(template: &str, input: &str) -> String {
+    let path = Path::new(input);
+    let part = |s: Option<&OsStr>| {
+        s.map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    let dir = path
+        .parent()
+        .map(|p| p.to_string_lossy())
+        .unwrap_or_default();
+
+    template
+        .replace("{stem}", &part(path.file_stem()))
+        .replace("{ext}", &part(path.extension()))
+        .replace("{name}", &part(path.file_name()))
+        .replace("{dir}", if dir.is_empty() { "." } else { &dir })
+}
+
+/// The output name of every input file, failing before anything is written if two inputs would
+/// be written to the same file
+pub fn unique_output_names(template: &str, files: &[String]) -> Result<Vec<String>, ImageneError> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    let mut outnames = Vec::with_capacity(files.len());
+    for file in files {
+        let outname = output_name(template, file);
+        if let Some(other) = seen.insert(outname.clone(), file) {
+            return Err(ImageneError::Parse(format!(
+                "{} and {} would both be written to {}",
+                other, file, outname
+            )));
+        }
+        outnames.push(outname);
+    }
+    Ok(outnames)
+}
+
+/// An input file together with the name of the file written, or why it failed
+pub type Outcome = (String, Result<String, ImageneError>);
+
+/// Run `pipeline` on every file, writing the results to the file named by `template`. Files are
+/// spread over one worker thread per CPU while the `extra` images actions refer to are loaded once
+/// and shared.
+///
+/// Returns the outcome for every input file in order.
+pub fn run(
+    pipeline: &Pipeline,
+    files: Vec<String>,
+    template: &str,
+    extra: &[String],
+) -> Result<Vec<Outcome>, ImageneError> {
+    let outnames = unique_output_names(template, &files)?;
+
+    let auto_orient = !pipeline.flag_is_enabled(&Flag::NoAutoOrient);
+    let mut loader = Images::load(extra.iter().cloned(), auto_orient);
+    let mut shared = HashMap::new();
+    for name in extra {
+        shared.insert(name.to_owned(), loader.get(name)?.clone());
+    }
+    let shared: Arc<HashMap<String, DynamicImage>> = Arc::new(shared);
+
+    let count = files.len();
+    let queue = Arc::new(Mutex::new(files.into_iter().zip(outnames).enumerate()));
+    let workers = num_cpus::get().min(count);
+
+    let (s, r) = mpsc::channel();
+    for _ in 0..workers {
+        let (s, queue, shared) = (s.clone(), queue.clone(), shared.clone());
+        let pipeline = pipeline.clone();
+        thread::spawn(move || {
+            let mut images = Images::new();
+            for (name, image) in shared.iter() {
+                images.insert(name.clone(), image.clone());
+            }
+            loop {
+                let next = queue.lock().unwrap().next();
+                let (i, (file, outname)) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let result = process(&pipeline, &file, &outname, &mut images).map(|_| outname);
+                if s.send((i, file, result)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(s);
+
+    let mut results: Vec<_> = r.iter().collect();
+    results.sort_by_key(|(i, _, _)| *i);
+    Ok(results
+        .into_iter()
+        .map(|(_, file, result)| (file, result))
+        .collect())
+}
+
+fn process(
+    pipeline: &Pipeline,
+    file: &str,
+    outname: &str,
+    images: &mut Images,
+) -> Result<(), ImageneError> {
+    let image = pipeline::open(file, !pipeline.flag_is_enabled(&Flag::NoAutoOrient))?;
+    let metadata = pipeline.metadata_from(file, images)?;
+    let pipeline = pipeline.clone().format(cli::output_format(outname));
+    let (image, out_format) = pipeline.run_with(file, image, images)?;
+
+    if let Some(dir) = Path::new(outname).parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)
+                .map_err(|e| ImageneError::Encode(outname.to_owned(), e.into()))?;
+        }
+    }
+    let mut encoded = Vec::new();
+    image
+        .write_to(&mut encoded, out_format)
+        .and_then(|_| fs::write(outname, metadata.embed(encoded)).map_err(Into::into))
+        .map_err(|e| ImageneError::Encode(outname.to_owned(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        matches(&pattern, &name.chars().collect::<Vec<char>>())
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(glob("*.jpg", "a.jpg"));
+        assert!(glob("*.jpg", ".jpg"));
+        assert!(!glob("*.jpg", "a.jpeg"));
+        assert!(glob("a*b*c", "aXXbYYc"));
+        assert!(!glob("a*b*c", "aXXbYY"));
+        assert!(glob("img_??.png", "img_01.png"));
+        assert!(!glob("img_??.png", "img_1.png"));
+        assert!(glob("*", ""));
+        assert!(!glob("?", ""));
+    }
+
+    #[test]
+    fn sets() {
+        assert!(glob("[abc].png", "b.png"));
+        assert!(!glob("[abc].png", "d.png"));
+        assert!(glob("img[0-9].png", "img7.png"));
+        assert!(!glob("img[0-9].png", "imgx.png"));
+        assert!(glob("[!a]*", "b"));
+        assert!(glob("[^a]*", "b"));
+        assert!(!glob("[!a]*", "abc"));
+        assert!(glob("[a-cx-z]", "y"));
+        assert!(glob("[-a]", "-"));
+        // An unclosed bracket is a literal
+        assert!(glob("[abc", "[abc"));
+        assert!(!glob("[abc", "a"));
+    }
+
+    #[test]
+    fn output_names() {
+        let input = "photos/trip/a.b.jpg";
+        assert_eq!(output_name("out/{stem}.png", input), "out/a.b.png");
+        assert_eq!(output_name("{dir}/{name}", input), "photos/trip/a.b.jpg");
+        assert_eq!(
+            output_name("{dir}/{stem}_small.{ext}", "a.jpg"),
+            "./a_small.jpg"
+        );
+    }
+
+    #[test]
+    fn colliding_output_names() {
+        let files = vec!["bin/a.jpg".to_owned(), "bin/a.png".to_owned()];
+        assert!(unique_output_names("out/{stem}.png", &files).is_err());
+        assert!(unique_output_names("out/result.png", &files).is_err());
+        assert_eq!(
+            unique_output_names("out/{stem}.{ext}.png", &files).unwrap(),
+            ["out/a.jpg.png", "out/a.png.png"]
+        );
+    }
+}
@@ -1,28 +1,32 @@
 use crate::action::Action;
 use crate::action::Action::*;
-use crate::action::{Direction, Flag, Orientation};
-use image::ImageOutputFormat;
+use crate::action::{
+    Align, Blend, Canvas, Direction, Flag, FontSize, Gravity, Length, Metadata, Orientation,
+    ResizeMode, Shadow, TextEffects, TextLayout, Tile,
+};
+use crate::batch;
+use crate::error::ImageneError;
+use image::{FilterType, ImageOutputFormat};
 use std::collections::HashMap;
-use std::process::exit;
+use std::str::FromStr;
 
 use colored::*;
-use std::env;
+
+mod script;
 
 pub struct Settings {
     pub actions: Vec<Action>,
+    /// Where each action was given, such as `argument 3` or `script.txt:7` for a line of a script
+    pub arguments: Vec<String>,
     pub flags: HashMap<Flag, bool>,
 }
 
-pub fn parse() -> ((String, String), Settings, Vec<String>) {
-    let mut settings = Settings {
-        actions: Vec::new(),
-        flags: HashMap::new(),
-    };
-    let args: Vec<String> = env::args().collect();
-
-    let help_message = format!(
-            "Syntax:
+/// The usage message shown for `--help` or when too few arguments are given
+pub fn help_message() -> String {
+    format!(
+        "Syntax:
     {imagene} {o}infile{c} ...{o}flag{c}... ...{o}action{c}:{o}value{c}... {o}outfile{c}
+    {imagene} --batch {o}glob{c} ...{o}flag{c}... ...{o}action{c}:{o}value{c}... {o}template{c}
 
 Available Actions:
     brightness:{o}int{c}           {comment} Increase brightness by percent
@@ -31,16 +35,46 @@ Available Actions:
     unsharpen:{o}float,int{c}      {comment} Add unsharpen mask with float being sigma and int being threshold
     invert:{o}true/false{c}        {comment} Invert colors of image
     flip:{o}v/h{c}                 {comment} Flip image v for vertically or h for horizontally
+    pad:{o}top,right,bottom,left,(r:g:b:a){c} {comment} Add margins of a color around an image
+    border:{o}int,(r:g:b:a){c}     {comment} Add a border of the same width in pixels on every side
+    canvas:{o}int,int,gravity,(r:g:b:a){c} {comment} Place an image on a larger canvas, gravity is center or a direction like north or southeast
     rotate:{o}left/right/down{c}   {comment} Rotate an image by 90,180,270 degrees
+    rotate:{o}float,expand/crop,(r:g:b:a){c} {comment} Rotate clockwise by degrees, growing the canvas or keeping its size. Uncovered corners are filled with the color
     resize:{o}int,int{c}           {comment} Resize an image, leave one of the ints empty to auto scale it
+    resize:{o}int,int,filter=name{c} {comment} Resize with a filter other than the one set by filter:
+    resize:{o}int,int,mode=fill/fit/cover/pad{c} {comment} Stretch to the size, fit inside of it, cover it and crop or fit and pad the rest
+                                     with gravity=center/north/southeast/... and for pad color=(r:g:b:a)
     crop:{o}int,int,int,int{c}     {comment} Crop an image (x,y,width,height)
-    append:{o}string,left/under{c} {comment} Add another image next to source image
-    text:{o}string,(x:y),(r:g:b:a),(font:scale){c}   {comment} Add text onto an image, \"font\" is a path to .ttf file and values are floats
+    append:{o}string,left/under{c} {comment} Add another image next to source image, also takes filter=name
+                                   gutter=int, color=(r:g:b:a) and noresize or align=start/center/end to keep its size
+    overlay:{o}string,x,y,float,mode{c} {comment} Draw another image on top at x,y with an opacity and a blend mode of normal multiply screen overlay darken lighten difference
+                                   tile=(angle:spacing:opacity) repeats it over the whole image instead
+    grid:{o}int,int,(r:g:b:a),string...{c} {comment} Lay out the image and the others (or globs) in columns with spacing and a background, also takes caption=(font:scale)
+    filter:{o}name{c}              {comment} Filter for every resize, one of nearest triangle catmullrom (default) gaussian lanczos3
+    text:{o}string,(x:y),(r:g:b:a),(font:scale){c}   {comment} Add text onto an image, \"font\" is a path to a font file, a family name like \"DejaVu Sans Bold\"
+                                   or empty for the bundled font, which is also used when it's left out. Positions are lengths or fractions of the image
+                                   file.ttc#1 picks a face of a collection and fonts joined by | fall back to the next for missing glyphs
+                                   Scale is tenths of the image width, or 24px, 12pt (at dpi=int, 72 by default), 5%w or 5%h
+                                   {{filename}} {{width}} {{height}} {{date}} and {{exif.DateTimeOriginal}} are filled in for each image, with the size it has at that step
+                                   \\n breaks lines and \\, is a comma, also takes anchor=gravity, align=left/center/right, width=length to wrap and spacing=float
+                                   outline=(width:r:g:b:a), shadow=(x:y:blur:r:g:b:a) and box=(padding:r:g:b:a) keep it readable
+                                   tile=(angle:spacing:opacity) repeats it over the whole image in rows turned clockwise by the angle
     format:{o}string{c}            {comment} Specify output image format
     format:{o}jpg,int{c}           {comment} For JPG, also specify quality
+    metadata:{o}keep/strip/icc-only{c} {comment} Carry EXIF, XMP and the color profile of the infile over to JPG and PNG outputs, strip is the default
+
+Lengths for resize, crop and text positions:
+    800 800px 50% -20 {comment} Pixels, percent of the image side or pixels less than the side, counting from the right or bottom edge for positions
+    0.25 1.0          {comment} Positions with a decimal point are fractions of the side, so 1.0 is the far edge while 1 is a pixel
+
+Output templates for --batch:
+    {{stem}} {{ext}} {{name}} {{dir}} {comment} File name without extension, extension, file name and directory of each input
 
 Available Flags:
-    lanczos3   {comment} Use a slower but possibly higher quality algorithm
+    lanczos3   {comment} Same as filter:lanczos3
+    --dry-run  {comment} Print every action with the resulting size and format without touching any pixels
+    --no-auto-orient {comment} Keep images as they're stored instead of turning them upright by their EXIF orientation
+    --script {o}file{c}  {comment} Read actions from a file, one per line, like `resize width=800 height=0`
 
 Examples:
      {comment} Increases the contrast of the original image by 20% and adds an extra image next to it
@@ -49,247 +83,738 @@ Examples:
      {comment} Set width to 2000, automatically scales height to keep aspect ratio and output to STDOUT
      {imagene} {infile} resize:2000,0 stdout
 
+     {comment} Resizes every jpg in a directory, writing photos/a.jpg to out/a.jpg and so on
+     {imagene} --batch 'photos/*.jpg' resize:800,0 format:jpg,85 'out/{{stem}}.jpg'
+
+     {comment} Shows what the output of a resize and crop would look like
+     {imagene} {infile} --dry-run resize:2000,0 crop:0,0,1000,1000 {outfile}
+
+     {comment} Reads an image from STDIN and writes the inverted image to STDOUT
+     cat {infile} | {imagene} stdin invert:true stdout
+
      {comment} Overwrites an image with increased contrast
      {imagene} {infile} contrast:2 {infile} ",
-            imagene = "imagene".green(),
-            infile = "in_file.png".blue(),
-            outfile = "out_file.png".blue(),
-            comment = "->".blue(),
-            o = "<".green(),
-            c = ">".green()
-        );
+        imagene = "imagene".green(),
+        infile = "in_file.png".blue(),
+        outfile = "out_file.png".blue(),
+        comment = "->".blue(),
+        o = "<".green(),
+        c = ">".green()
+    )
+}
+
+/// Parse the command line into the in/out files, the settings and the names of every image that
+/// needs to be loaded. Returns `None` when the help message should be shown instead.
+#[allow(clippy::type_complexity)]
+pub fn parse(
+    args: &[String],
+) -> Result<Option<((String, String), Settings, Vec<String>)>, ImageneError> {
+    let mut settings = Settings {
+        actions: Vec::new(),
+        arguments: Vec::new(),
+        flags: HashMap::new(),
+    };
 
     if args.len() <= 3 {
-        println!("{}", help_message);
-        exit(0);
+        return Ok(None);
     }
     if &args[1] == "--help" || &args[1] == "-h" || &args[1] == "/h" || &args[1] == "help" {
-        println!("{}", help_message);
-        exit(0);
+        return Ok(None);
     }
 
-    let infile = &args[1];
-    let outfile = &args[args.len() - 1];
-    if outfile != "stdout" {
-        println!("Using infile {} and outfile {}", infile, outfile);
+    // In batch mode the infile is a glob pattern and the outfile a template
+    let first = if &args[1] == "--batch" {
+        if args.len() <= 4 {
+            return Ok(None);
+        }
+        settings.flags.insert(Flag::Batch, true);
+        2
+    } else {
+        1
     };
 
+    let infile = &args[first];
+    let outfile = &args[args.len() - 1];
+
     let mut images: Vec<String> = Vec::new();
     images.push(infile.to_owned());
 
-    for arg in &args[2..args.len() - 1] {
-        let step = split_kv(arg);
+    let mut i = first + 1;
+    while i < args.len() - 1 {
+        if args[i] == "--script" {
+            i += 1;
+            if i == args.len() - 1 {
+                return Err(ImageneError::Parse(
+                    "--script needs a file to read actions from".to_owned(),
+                ));
+            }
+            parse_script(&args[i], &mut settings, &mut images)?;
+        } else {
+            let origin = format!("argument {}", i);
+            parse_argument(origin, &args[i], false, &mut settings, &mut images)?;
+        }
+        i += 1;
+    }
 
-        match step {
-            Ok((k, v)) => {
-                // Key:Value based argument
-                settings.actions.push(match k {
-                    "invert" => {
-                        if v == "true" || v == "1" {
-                            Invert
-                        } else {
-                            continue;
-                        }
+    Ok(Some((
+        (infile.to_owned(), outfile.to_owned()),
+        settings,
+        images,
+    )))
+}
+
+/// Parse a single `action:value` or flag argument given at `origin` into `settings`. The names of
+/// any images it refers to are added to `images`.
+fn parse_argument(
+    origin: String,
+    arg: &str,
+    in_script: bool,
+    settings: &mut Settings,
+    images: &mut Vec<String>,
+) -> Result<(), ImageneError> {
+    let step = split_kv(arg);
+
+    match step {
+        Ok((k, v)) => {
+            // Key:Value based argument
+            settings.actions.push(match k {
+                "invert" => {
+                    if v == "true" || v == "1" {
+                        Invert
+                    } else {
+                        return Ok(());
                     }
-                    "contrast" => Contrast(v.to_owned().parse::<f32>().unwrap_or_else(|_| {
-                        eprintln!("{}: Invalid value for {}", k, v);
-                        exit(2)
-                    })),
-                    "brightness" => Brightness(v.to_owned().parse::<i32>().unwrap_or_else(|_| {
-                        eprintln!("{}: Invalid value for {}", k, v);
-                        exit(2)
-                    })),
-                    "blur" => Blur(v.to_owned().parse::<f32>().unwrap_or_else(|_| {
-                        eprintln!("{}: Invalid value for {}", k, v);
-                        exit(2)
-                    })),
-                    "crop" => {
-                        let crop_arguments: Vec<&str> = v.split(",").collect();
-                        if crop_arguments.len() != 4 {
-                            eprintln!("Wrong amount of arguments for crop, i need \"x,y,w,h\"");
-                            exit(2);
-                        }
-                        let convert = |s: &str| {
-                            s.to_owned().parse::<u32>().unwrap_or_else(|_| {
-                                eprintln!("{}: Invalid value for {}", s, k);
-                                exit(2);
-                            })
-                        };
-                        Crop(
-                            convert(crop_arguments[0]),
-                            convert(crop_arguments[1]),
-                            convert(crop_arguments[2]),
-                            convert(crop_arguments[3]),
-                        )
+                }
+                "contrast" => Contrast(parse_value(k, &v)?),
+                "brightness" => Brightness(parse_value(k, &v)?),
+                "blur" => Blur(parse_value(k, &v)?),
+                "crop" => {
+                    let crop_arguments: Vec<&str> = split_arguments(&v);
+                    if crop_arguments.len() != 4 {
+                        return Err(ImageneError::Parse(
+                            "Wrong amount of arguments for crop, i need \"x,y,w,h\"".to_owned(),
+                        ));
                     }
-                    "rotate" => Rotate(match v.as_str() {
-                        "down" => Direction::Down,
-                        "left" => Direction::Left,
-                        "right" => Direction::Right,
+                    Crop(
+                        parse_value(k, crop_arguments[0])?,
+                        parse_value(k, crop_arguments[1])?,
+                        parse_value(k, crop_arguments[2])?,
+                        parse_value(k, crop_arguments[3])?,
+                    )
+                }
+                "pad" => {
+                    let pad_arguments: Vec<&str> = split_arguments(&v);
+                    if pad_arguments.len() != 5 {
+                        return Err(ImageneError::Parse(
+                            "Wrong amount of arguments for pad, i need \"top,right,bottom,left,(r:g:b:a)\"".to_owned(),
+                        ));
+                    }
+                    Pad(
+                        parse_value(k, pad_arguments[0])?,
+                        parse_value(k, pad_arguments[1])?,
+                        parse_value(k, pad_arguments[2])?,
+                        parse_value(k, pad_arguments[3])?,
+                        parse_color(k, pad_arguments[4])?,
+                    )
+                }
+                "border" => {
+                    let border_arguments: Vec<&str> = split_arguments(&v);
+                    if border_arguments.len() != 2 {
+                        return Err(ImageneError::Parse(
+                            "Wrong amount of arguments for border, i need \"width,(r:g:b:a)\"".to_owned(),
+                        ));
+                    }
+                    // A border is the same padding on every side, in pixels since a percentage
+                    // would be a different width on the sides than on the top and bottom
+                    let width = match parse_value(k, border_arguments[0])? {
+                        Length::Pixels(width) => Length::Pixels(width),
                         _ => {
-                            eprintln!("Invalid value for rotate, use left right or down");
-                            exit(2)
+                            return Err(ImageneError::Parse(format!(
+                                "{}: Invalid value for border, use a width in pixels",
+                                border_arguments[0]
+                            )))
                         }
-                    }),
-                    "flip" => match v.as_str() {
-                        "v" => Flip(Orientation::Vertical),
-                        "h" => Flip(Orientation::Horizontal),
-                        _ => {
-                            eprintln!("Invalid value for flip, use v or h");
-                            exit(2)
+                    };
+                    Pad(width, width, width, width, parse_color(k, border_arguments[1])?)
+                }
+                "canvas" => {
+                    let canvas_arguments: Vec<&str> = split_arguments(&v);
+                    if canvas_arguments.len() != 4 {
+                        return Err(ImageneError::Parse(
+                            "Wrong amount of arguments for canvas, i need \"width,height,gravity,(r:g:b:a)\"".to_owned(),
+                        ));
+                    }
+                    Extend(
+                        parse_value(k, canvas_arguments[0])?,
+                        parse_value(k, canvas_arguments[1])?,
+                        parse_gravity(canvas_arguments[2])?,
+                        parse_color(k, canvas_arguments[3])?,
+                    )
+                }
+                "rotate" => {
+                    let rotate_arguments: Vec<&str> = split_arguments(&v);
+                    match rotate_arguments[0] {
+                        "down" | "left" | "right" if rotate_arguments.len() > 1 => {
+                            return Err(ImageneError::Parse(format!(
+                                "rotate:{} takes no other values, only rotating by degrees does",
+                                rotate_arguments[0]
+                            )))
+                        }
+                        "down" => Rotate(Direction::Down),
+                        "left" => Rotate(Direction::Left),
+                        "right" => Rotate(Direction::Right),
+                        degrees => {
+                            let degrees = parse_value::<f32>(k, degrees)
+                                .ok()
+                                .filter(|degrees| degrees.is_finite())
+                                .ok_or_else(|| {
+                                    ImageneError::Parse(
+                                        "Invalid value for rotate, use left right down or degrees"
+                                            .to_owned(),
+                                    )
+                                })?;
+                            let mut canvas = Canvas::Expand;
+                            let mut color = (0.0, 0.0, 0.0, 0.0);
+                            for argument in &rotate_arguments[1..] {
+                                match *argument {
+                                    "expand" => canvas = Canvas::Expand,
+                                    "crop" => canvas = Canvas::Crop,
+                                    _ => color = parse_color(k, argument)?,
+                                }
+                            }
+                            RotateBy(degrees, canvas, color)
                         }
-                    },
-                    "unsharpen" => {
-                        let unsharp_arguments: Vec<&str> = v.split(",").collect();
-                        if unsharp_arguments.len() != 2 {
-                            {
-                                eprintln!("Wrong amount of arguments for unsharpen")
-                            };
-                            exit(2)
-                        };
-                        let convert = |s: &str| {
-                            s.to_owned().parse::<f32>().unwrap_or_else(|_| {
-                                eprintln!("{}: Invalid value for {}", s, k,);
-                                exit(2)
-                            })
-                        };
-                        Unsharpen(
-                            convert(unsharp_arguments[0]),
-                            convert(unsharp_arguments[1]) as i32,
-                        )
                     }
-                    "resize" => {
-                        let resize_arguments: Vec<&str> = v.split(",").collect();
-                        if resize_arguments.len() != 2 {
-                            eprintln!("Wrong amount of arguments for resize");
-                            exit(2);
-                        };
-                        Scale(
-                            resize_arguments[0]
-                                .to_owned()
-                                .parse::<u32>()
-                                .unwrap_or_else(|_| {
-                                    eprintln!("{}: Invalid value for {}", resize_arguments[0], v,);
-                                    exit(2)
-                                }),
-                            resize_arguments[1]
-                                .to_owned()
-                                .parse::<u32>()
-                                .unwrap_or_else(|_| {
-                                    eprintln!("{}: Invalid value for {}", resize_arguments[1], v,);
-                                    exit(2);
-                                }),
-                        )
+                }
+                "flip" => match v.as_str() {
+                    "v" => Flip(Orientation::Vertical),
+                    "h" => Flip(Orientation::Horizontal),
+                    _ => {
+                        return Err(ImageneError::Parse(
+                            "Invalid value for flip, use v or h".to_owned(),
+                        ))
                     }
-                    "append" => {
-                        let append_arguments: Vec<&str> = v.split(",").collect();
-                        if append_arguments.len() != 2 {
-                            eprintln!("Wrong amount of arguments for append");
-                            exit(2);
-                        };
-                        images.push(append_arguments[0].to_owned());
-                        Append(
-                            append_arguments[0].to_owned(),
-                            match append_arguments[1] {
-                                "left" => Direction::Left,
-                                "right" => Direction::Right,
-                                "down" => Direction::Down,
-                                "under" => Direction::Down,
-                                "up" => Direction::Up,
-                                "over" => Direction::Up,
-                                _ => {
-                                    eprintln!("Second parameter invalid for append");
-                                    exit(2)
-                                }
-                            },
-                        )
+                },
+                "unsharpen" => {
+                    let unsharp_arguments: Vec<&str> = split_arguments(&v);
+                    if unsharp_arguments.len() != 2 {
+                        return Err(ImageneError::Parse(
+                            "Wrong amount of arguments for unsharpen".to_owned(),
+                        ));
+                    };
+                    Unsharpen(
+                        parse_value(k, unsharp_arguments[0])?,
+                        parse_value::<f32>(k, unsharp_arguments[1])? as i32,
+                    )
+                }
+                "resize" => {
+                    let resize_arguments: Vec<&str> = split_arguments(&v);
+                    if resize_arguments.len() < 2 {
+                        return Err(ImageneError::Parse(
+                            "Wrong amount of arguments for resize".to_owned(),
+                        ));
+                    };
+                    let options = parse_options(
+                        k,
+                        &resize_arguments[2..],
+                        &["filter", "mode", "gravity", "color"],
+                    )?;
+                    let gravity = options
+                        .get("gravity")
+                        .map(|g| parse_gravity(g))
+                        .transpose()?
+                        .unwrap_or(Gravity::Center);
+                    let mode = match options.get("mode").cloned().unwrap_or("fill") {
+                        "fill" => ResizeMode::Fill,
+                        "fit" => ResizeMode::Fit,
+                        "cover" => ResizeMode::Cover(gravity),
+                        "pad" => ResizeMode::Pad(
+                            gravity,
+                            options
+                                .get("color")
+                                .map(|c| parse_color(k, c))
+                                .transpose()?
+                                .unwrap_or((0.0, 0.0, 0.0, 0.0)),
+                        ),
+                        mode => {
+                            return Err(ImageneError::Parse(format!(
+                                "{}: Invalid mode for resize, use fill fit cover or pad",
+                                mode
+                            )))
+                        }
+                    };
+                    Scale(
+                        parse_value(k, resize_arguments[0])?,
+                        parse_value(k, resize_arguments[1])?,
+                        options.get("filter").map(|f| parse_filter(f)).transpose()?,
+                        mode,
+                    )
+                }
+                "append" => {
+                    let append_arguments: Vec<&str> = split_arguments(&v);
+                    if append_arguments.len() < 2 {
+                        return Err(ImageneError::Parse(
+                            "Wrong amount of arguments for append".to_owned(),
+                        ));
+                    };
+                    // A bare noresize is the only option without a value
+                    let (noresize, options): (Vec<&str>, Vec<&str>) = append_arguments[2..]
+                        .iter()
+                        .partition(|a| **a == "noresize");
+                    let options =
+                        parse_options(k, &options, &["filter", "gutter", "color", "align"])?;
+                    let align = match options.get("align") {
+                        None if noresize.is_empty() => None,
+                        None => Some(Align::Center),
+                        Some(align) => Some(parse_align(align)?),
+                    };
+                    images.push(unescape_commas(append_arguments[0]));
+                    Append {
+                        image: unescape_commas(append_arguments[0]),
+                        direction: match append_arguments[1] {
+                            "left" => Direction::Left,
+                            "right" => Direction::Right,
+                            "down" => Direction::Down,
+                            "under" => Direction::Down,
+                            "up" => Direction::Up,
+                            "over" => Direction::Up,
+                            _ => {
+                                return Err(ImageneError::Parse(
+                                    "Second parameter invalid for append".to_owned(),
+                                ))
+                            }
+                        },
+                        filter: options.get("filter").map(|f| parse_filter(f)).transpose()?,
+                        gutter: options
+                            .get("gutter")
+                            .map(|g| parse_value(k, g))
+                            .transpose()?
+                            .unwrap_or(0),
+                        background: options
+                            .get("color")
+                            .map(|c| parse_color(k, c))
+                            .transpose()?
+                            .unwrap_or((0.0, 0.0, 0.0, 0.0)),
+                        align,
                     }
-                    "format" => {
-                        let format_arguments: Vec<&str> = v.split(",").collect();
-                        if format_arguments.len() == 2 {
-                            Format(ImageOutputFormat::JPEG(
-                                format_arguments[1]
-                                    .to_owned()
-                                    .parse::<u8>()
-                                    .unwrap_or_else(|_| {
-                                        eprintln!(
-                                            "{}: Invalid format for {}",
-                                            format_arguments[1], format_arguments[0]
-                                        );
-                                        exit(2);
-                                    }),
-                            ))
+                }
+                "overlay" => {
+                    let (overlay_arguments, options): (Vec<&str>, Vec<&str>) =
+                        split_arguments(&v).into_iter().partition(|a| !a.contains('='));
+                    let options = parse_options(k, &options, &["tile"])?;
+                    if overlay_arguments.len() < 3 || overlay_arguments.len() > 5 {
+                        return Err(ImageneError::Parse(
+                            "Wrong amount of arguments for overlay, i need \"image,x,y\" and optionally \"opacity,mode\"".to_owned(),
+                        ));
+                    }
+                    images.push(unescape_commas(overlay_arguments[0]));
+                    Overlay(
+                        unescape_commas(overlay_arguments[0]),
+                        (
+                            parse_value(k, overlay_arguments[1])?,
+                            parse_value(k, overlay_arguments[2])?,
+                        ),
+                        overlay_arguments
+                            .get(3)
+                            .map(|o| parse_value(k, o))
+                            .transpose()?
+                            .unwrap_or(1.0),
+                        match overlay_arguments.get(4).cloned().unwrap_or("normal") {
+                            "normal" => Blend::Normal,
+                            "multiply" => Blend::Multiply,
+                            "screen" => Blend::Screen,
+                            "overlay" => Blend::Overlay,
+                            "darken" => Blend::Darken,
+                            "lighten" => Blend::Lighten,
+                            "difference" => Blend::Difference,
+                            mode => {
+                                return Err(ImageneError::Parse(format!(
+                                    "{}: Invalid blend mode, use normal multiply screen overlay darken lighten or difference",
+                                    mode
+                                )))
+                            }
+                        },
+                        options.get("tile").map(|t| parse_tile(k, t)).transpose()?,
+                    )
+                }
+                "grid" => {
+                    let grid_arguments: Vec<&str> = split_arguments(&v);
+                    if grid_arguments.len() < 4 {
+                        return Err(ImageneError::Parse(
+                            "Wrong amount of arguments for grid, i need \"columns,spacing,(r:g:b:a)\" and at least one image".to_owned(),
+                        ));
+                    }
+                    let (names, options): (Vec<&str>, Vec<&str>) =
+                        grid_arguments[3..].iter().partition(|a| !a.contains('='));
+                    let options = parse_options(k, &options, &["caption", "dpi"])?;
+                    let mut filenames = Vec::new();
+                    for name in names {
+                        let name = unescape_commas(name);
+                        // Globs are expanded like for --batch
+                        if name.contains(&['*', '?', '['][..]) {
+                            filenames.extend(batch::expand(&name)?);
                         } else {
-                            Format(match format_arguments[0] {
-                                "png" => ImageOutputFormat::PNG,
-                                "gif" => ImageOutputFormat::GIF,
-                                "bmp" => ImageOutputFormat::BMP,
-                                "ico" => ImageOutputFormat::ICO,
-                                &_ => {
-                                    eprintln!("Invalid value for format");
-                                    exit(2)
-                                }
-                            })
+                            filenames.push(name);
                         }
                     }
-                    "watermark" | "write" | "text" => {
-                        let text_arguments: Vec<&str> = v.split(",").collect();
-                        if text_arguments.len() != 4 {
-                            eprintln!("Wrong amount of arguments for {:?}", text_arguments);
-                            exit(2);
-                        };
-                        let content = text_arguments[0];
-                        let position = {
-                            let sub = sub_category_parse(text_arguments[1], 2).expect(
-                                &format!("Unexpected format for position of watermark/write/text. Expected (0.3 : 0.5), got {}", text_arguments[1]));
-                            let sub: Vec<f32> = sub.into_iter()
-                                .map(|s| s.parse()
-                                     .expect("Invalid value for position of watermark/write/text. Expected float"))
-                                .collect();
-                            (sub[0], sub[1])
-                        };
-                        let color_rgba = {
-                            let sub = sub_category_parse(text_arguments[2], 4).expect(
-                                &format!("Unexpected format for color of watermark/write/text. Expected (1.0 : 0.0 : 0.0 : 0.5), got {}", text_arguments[2]));
-                            let sub: Vec<f32> = sub.into_iter()
-                                .map(|s| s.parse()
-                                     .expect("Invalid value for position of watermark/write/text. Expected float"))
-                                .collect();
-                            (sub[0], sub[1], sub[2], sub[3])
-                        };
-                        let font = {
-                            let sub = sub_category_parse(text_arguments[3], 2).expect(
-                                &format!("Unexpected format for color of watermark/write/text. Expected (1.0 : 0.0 : 0.0 : 0.5), got {}", text_arguments[2]));
-                            (sub[0].to_owned(), sub[1].parse::<f32>().expect("Invalid value for position of watermark/write/text. expected float"))
-                        };
-                        Watermark(
-                            content.to_owned(),
-                            position,
-                            color_rgba,
-                            font,
-                        )
+                    images.extend(filenames.iter().cloned());
+                    let caption = match options.get("caption") {
+                        Some(font) => {
+                            let sub = sub_category_parse(font, 2).map_err(|_| {
+                                ImageneError::Parse(format!(
+                                    "Unexpected format for caption of grid. Expected (font.ttf : 0.5), got {}",
+                                    font
+                                ))
+                            })?;
+                            let size = parse_font_size(k, &sub[1], options.get("dpi"))?;
+                            Some((sub[0].to_owned(), size))
+                        }
+                        None => None,
+                    };
+                    Grid(
+                        filenames,
+                        parse_value(k, grid_arguments[0])?,
+                        parse_value(k, grid_arguments[1])?,
+                        parse_color(k, grid_arguments[2])?,
+                        caption,
+                    )
+                }
+                "format" => {
+                    let format_arguments: Vec<&str> = split_arguments(&v);
+                    if format_arguments.len() == 2 {
+                        Format(ImageOutputFormat::JPEG(parse_value(
+                            format_arguments[0],
+                            format_arguments[1],
+                        )?))
+                    } else {
+                        Format(match format_arguments[0] {
+                            "png" => ImageOutputFormat::PNG,
+                            "gif" => ImageOutputFormat::GIF,
+                            "bmp" => ImageOutputFormat::BMP,
+                            "ico" => ImageOutputFormat::ICO,
+                            &_ => {
+                                return Err(ImageneError::Parse(
+                                    "Invalid value for format".to_owned(),
+                                ))
+                            }
+                        })
                     }
-                    &_ => {
-                        eprintln!("{}: action not found", k);
-                        exit(2);
+                }
+                "metadata" => Action::Metadata(match v.as_ref() {
+                    "keep" => Metadata::Keep,
+                    "strip" => Metadata::Strip,
+                    "icc-only" => Metadata::IccOnly,
+                    _ => {
+                        return Err(ImageneError::Parse(format!(
+                            "Invalid value for metadata: {}, expected keep, strip or icc-only",
+                            v
+                        )))
                     }
-                });
-            }
-            Err(err) => {
-                // Flag based argument
-                let name: &str = arg.as_ref();
-                match name {
-                    "lanczos3" => settings.flags.insert(Flag::Lanczos3, true),
-                    &_ => {
-                        eprintln!("Unrecognized argument \"{}\"\n{}", arg, err);
-                        exit(2);
+                }),
+                "filter" => Filter(parse_filter(&v)?),
+                "script" if in_script => {
+                    return Err(ImageneError::Parse(
+                        "scripts can't include other scripts".to_owned(),
+                    ))
+                }
+                "script" => return parse_script(&v, settings, images),
+                "watermark" | "write" | "text" => {
+                    let text_arguments: Vec<&str> = split_arguments(&v);
+                    if text_arguments.len() < 3 {
+                        return Err(ImageneError::Parse(format!(
+                            "Wrong amount of arguments for {:?}",
+                            text_arguments
+                        )));
+                    };
+                    // A literal \n breaks the line
+                    let content = unescape_commas(text_arguments[0]).replace("\\n", "\n");
+                    let position = {
+                        let sub = sub_category_parse(text_arguments[1], 2).map_err(|_| ImageneError::Parse(
+                            format!("Unexpected format for position of watermark/write/text. Expected (0.3 : 0.5), got {}", text_arguments[1])))?;
+                        (parse_position(k, &sub[0])?, parse_position(k, &sub[1])?)
+                    };
+                    let color_rgba = parse_color(k, text_arguments[2])?;
+                    let given_font = text_arguments.get(3).filter(|f| !f.contains('='));
+                    let options = parse_options(
+                        k,
+                        &text_arguments[3 + given_font.is_some() as usize..],
+                        &[
+                            "anchor", "align", "width", "spacing", "outline", "shadow", "box", "dpi",
+                            "tile",
+                        ],
+                    )?;
+                    // Without a font the bundled one is used at its default size
+                    let font = match given_font {
+                        None => (String::new(), FontSize::Relative(1.0)),
+                        Some(font) => {
+                            let sub = sub_category_parse(font, 2).map_err(|_| ImageneError::Parse(
+                                format!("Unexpected format for font of watermark/write/text. Expected (font.ttf : 1.0), got {}", font)))?;
+                            (sub[0].to_owned(), parse_font_size(k, &sub[1], options.get("dpi"))?)
+                        }
+                    };
+                    let default = TextLayout::default();
+                    let layout = TextLayout {
+                        anchor: match options.get("anchor") {
+                            Some(anchor) => parse_gravity(anchor)?,
+                            None => default.anchor,
+                        },
+                        align: match options.get("align") {
+                            Some(align) => parse_align(align)?,
+                            None => default.align,
+                        },
+                        max_width: options
+                            .get("width")
+                            .map(|w| parse_value(k, w))
+                            .transpose()?,
+                        spacing: match options.get("spacing") {
+                            Some(spacing) => parse_value(k, spacing)?,
+                            None => default.spacing,
+                        },
+                    };
+                    // Effects are a size followed by a color in one sub category
+                    let effect = |name: &str, count: usize| -> Result<Option<Vec<String>>, ImageneError> {
+                        options
+                            .get(name)
+                            .map(|value| {
+                                sub_category_parse(value, count).map_err(|_| {
+                                    ImageneError::Parse(format!(
+                                        "{}: Unexpected format for {} of text, expected {} values",
+                                        value, name, count
+                                    ))
+                                })
+                            })
+                            .transpose()
+                    };
+                    let color_from = |sub: &[String]| -> Result<_, ImageneError> {
+                        parse_color(k, &sub.join(":"))
+                    };
+                    let effects = TextEffects {
+                        outline: match effect("outline", 5)? {
+                            Some(sub) => Some((parse_value(k, &sub[0])?, color_from(&sub[1..])?)),
+                            None => None,
+                        },
+                        shadow: match effect("shadow", 7)? {
+                            Some(sub) => Some(Shadow {
+                                offset: (parse_value(k, &sub[0])?, parse_value(k, &sub[1])?),
+                                blur: parse_value(k, &sub[2])?,
+                                color: color_from(&sub[3..])?,
+                            }),
+                            None => None,
+                        },
+                        background: match effect("box", 5)? {
+                            Some(sub) => Some((parse_value(k, &sub[0])?, color_from(&sub[1..])?)),
+                            None => None,
+                        },
+                    };
+                    let tile = options.get("tile").map(|t| parse_tile(k, t)).transpose()?;
+                    Watermark {
+                        text: content,
+                        position,
+                        color: color_rgba,
+                        font,
+                        layout,
+                        effects,
+                        tile,
                     }
-                };
+                }
+                &_ => {
+                    return Err(ImageneError::Parse(format!("{}: action not found", k)));
+                }
+            });
+            settings.arguments.push(origin);
+        }
+        Err(err) => {
+            // Flag based argument
+            match arg {
+                "lanczos3" => settings.flags.insert(Flag::Lanczos3, true),
+                "dry-run" | "--dry-run" => settings.flags.insert(Flag::DryRun, true),
+                "no-auto-orient" | "--no-auto-orient" => {
+                    settings.flags.insert(Flag::NoAutoOrient, true)
+                }
+                &_ => {
+                    return Err(ImageneError::Parse(format!(
+                        "Unrecognized argument \"{}\"\n{}",
+                        arg, err
+                    )));
+                }
+            };
+        }
+    }
+    Ok(())
+}
+
+/// Parse every action of a script file as if it was given on the command line, where each action
+/// is reported by its line
+fn parse_script(
+    path: &str,
+    settings: &mut Settings,
+    images: &mut Vec<String>,
+) -> Result<(), ImageneError> {
+    for (line, arg) in script::read(path)? {
+        let origin = format!("{}:{}", path, line);
+        parse_argument(origin, &arg, true, settings, images).map_err(|e| match e {
+            ImageneError::Parse(msg) => ImageneError::Parse(format!("{}:{}: {}", path, line, msg)),
+            e => e,
+        })?;
+    }
+    Ok(())
+}
+
+/// Parse a single value of an action, naming the action in the error
+fn parse_value<T: FromStr>(key: &str, s: &str) -> Result<T, ImageneError> {
+    s.parse()
+        .map_err(|_| ImageneError::Parse(format!("{}: Invalid value for {}", s, key)))
+}
+
+/// Parse trailing `name=value` options of an action, only accepting the `allowed` names
+fn parse_options<'a>(
+    key: &str,
+    arguments: &[&'a str],
+    allowed: &[&str],
+) -> Result<HashMap<&'a str, &'a str>, ImageneError> {
+    let mut options = HashMap::new();
+    for argument in arguments {
+        match argument.find('=') {
+            Some(i) if allowed.contains(&&argument[..i]) => {
+                options.insert(&argument[..i], &argument[i + 1..]);
+            }
+            _ => {
+                return Err(ImageneError::Parse(format!(
+                    "{}: Unknown option for {}, expected one of {}",
+                    argument,
+                    key,
+                    allowed
+                        .iter()
+                        .map(|name| format!("{}=", name))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                )))
             }
         }
     }
+    Ok(options)
+}
+
+/// Parse the name of a resampling filter
+fn parse_filter(s: &str) -> Result<FilterType, ImageneError> {
+    Ok(match s {
+        "nearest" => FilterType::Nearest,
+        "triangle" | "bilinear" => FilterType::Triangle,
+        "catmullrom" | "bicubic" => FilterType::CatmullRom,
+        "gaussian" => FilterType::Gaussian,
+        "lanczos3" => FilterType::Lanczos3,
+        _ => {
+            return Err(ImageneError::Parse(format!(
+                "{}: Invalid filter, use nearest triangle catmullrom gaussian or lanczos3",
+                s
+            )))
+        }
+    })
+}
+
+/// Parse a position like any other length, except that a number with a decimal point such as
+/// `0.25` is a fraction of the side the way text has always been placed. Whole numbers are
+/// pixels, so `1.0` is the far edge and `1` is a pixel.
+fn parse_position(key: &str, s: &str) -> Result<Length, ImageneError> {
+    if !s.contains('.') || s.ends_with('%') {
+        return parse_value(key, s);
+    }
+    match s.parse::<f32>() {
+        Ok(fraction) if fraction.is_finite() && fraction >= 0.0 => {
+            Ok(Length::Percent(fraction * 100.0))
+        }
+        _ => Err(ImageneError::Parse(format!(
+            "{}: Invalid position for {}, use a fraction like 0.5 or a length",
+            s, key
+        ))),
+    }
+}
 
-    ((infile.to_owned(), outfile.to_owned()), settings, images)
+/// Parse a font size, where points are at `dpi` if it's given
+fn parse_font_size(key: &str, s: &str, dpi: Option<&&str>) -> Result<FontSize, ImageneError> {
+    match (parse_value(key, s)?, dpi) {
+        (FontSize::Points(points, _), Some(dpi)) => match dpi.parse::<f32>() {
+            Ok(dpi) if dpi.is_finite() && dpi > 0.0 => Ok(FontSize::Points(points, dpi)),
+            _ => Err(ImageneError::Parse(format!(
+                "{}: Invalid dpi for {}",
+                dpi, key
+            ))),
+        },
+        (size, _) => Ok(size),
+    }
+}
+
+/// Parse `(angle:spacing)` or `(angle:spacing:opacity)` of a repeated watermark
+fn parse_tile(key: &str, s: &str) -> Result<Tile, ImageneError> {
+    let sub = sub_category_parse(s, 3)
+        .or_else(|_| sub_category_parse(s, 2))
+        .map_err(|_| {
+            ImageneError::Parse(format!(
+                "Unexpected format for tile of {}. Expected (angle:spacing:opacity), got {}",
+                key, s
+            ))
+        })?;
+    Ok(Tile {
+        angle: parse_value(key, &sub[0])?,
+        spacing: parse_value(key, &sub[1])?,
+        opacity: sub
+            .get(2)
+            .map(|o| parse_value(key, o))
+            .transpose()?
+            .unwrap_or(1.0),
+    })
+}
+
+/// Parse an alignment, where left and top are the start and right and bottom the end
+fn parse_align(s: &str) -> Result<Align, ImageneError> {
+    Ok(match s {
+        "start" | "left" | "top" => Align::Start,
+        "center" => Align::Center,
+        "end" | "right" | "bottom" => Align::End,
+        _ => {
+            return Err(ImageneError::Parse(format!(
+                "{}: Invalid align, use start center or end",
+                s
+            )))
+        }
+    })
+}
+
+/// Parse a gravity given as a compass direction like `north` or `southeast`, or `center`
+fn parse_gravity(s: &str) -> Result<Gravity, ImageneError> {
+    Ok(match s {
+        "center" => Gravity::Center,
+        "north" => Gravity::North,
+        "northeast" => Gravity::NorthEast,
+        "east" => Gravity::East,
+        "southeast" => Gravity::SouthEast,
+        "south" => Gravity::South,
+        "southwest" => Gravity::SouthWest,
+        "west" => Gravity::West,
+        "northwest" => Gravity::NorthWest,
+        _ => {
+            return Err(ImageneError::Parse(format!(
+                "{}: Invalid gravity, use center or a direction like north or southeast",
+                s
+            )))
+        }
+    })
+}
+
+/// Parse a color given as `(r:g:b:a)` where every channel is a float between 0 and 1
+fn parse_color(key: &str, s: &str) -> Result<(f32, f32, f32, f32), ImageneError> {
+    let sub = sub_category_parse(s, 4).map_err(|_| {
+        ImageneError::Parse(format!(
+            "Unexpected format for color of {}. Expected (1.0 : 0.0 : 0.0 : 0.5), got {}",
+            key, s
+        ))
+    })?;
+    Ok((
+        parse_value(key, &sub[0])?,
+        parse_value(key, &sub[1])?,
+        parse_value(key, &sub[2])?,
+        parse_value(key, &sub[3])?,
+    ))
 }
 
 fn split_kv(s: &str) -> Result<(&str, String), String> {
@@ -315,6 +840,27 @@ fn split_kv(s: &str) -> Result<(&str, String), String> {
     }
 }
 
+/// Split the values of an argument at every comma that isn't escaped as `\,`
+fn split_arguments(v: &str) -> Vec<&str> {
+    let mut arguments = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in v.char_indices() {
+        if c == ',' && !escaped {
+            arguments.push(&v[start..i]);
+            start = i + 1;
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    arguments.push(&v[start..]);
+    arguments
+}
+
+/// Turn the escaped commas of a value back into commas
+fn unescape_commas(s: &str) -> String {
+    s.replace("\\,", ",")
+}
+
 fn sub_category_parse(c: &str, args: usize) -> Result<Vec<String>, &str> {
     let sub_category = escape(String::from(c));
 
@@ -351,3 +897,60 @@ fn escape(c: String) -> String {
 pub fn flag_is_enabled(v: Option<&bool>) -> bool {
     *v.unwrap_or(&false)
 }
+
+/// Whether an infile name means the image should be read from stdin
+pub fn is_stdin(name: &str) -> bool {
+    name == "stdin" || name == "-"
+}
+
+/// Output format implied by the extension of the outfile, defaulting to PNG
+pub fn output_format(outfile: &str) -> ImageOutputFormat {
+    match outfile.rsplit('.').next().unwrap_or("") {
+        "png" => ImageOutputFormat::PNG,
+        "jpg" => ImageOutputFormat::JPEG(100),
+        "jpeg" => ImageOutputFormat::JPEG(100),
+        "bmp" => ImageOutputFormat::BMP,
+        "gif" => ImageOutputFormat::GIF,
+        "ico" => ImageOutputFormat::ICO,
+        &_ => ImageOutputFormat::PNG,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions() {
+        let position = |s| parse_position("text", s).ok();
+        assert_eq!(position("0.25"), Some(Length::Percent(25.0)));
+        assert_eq!(position("1.0"), Some(Length::Percent(100.0)));
+        assert_eq!(position("1.5"), Some(Length::Percent(150.0)));
+        assert_eq!(position(".5"), Some(Length::Percent(50.0)));
+        assert_eq!(position("0"), Some(Length::Pixels(0)));
+        assert_eq!(position("1"), Some(Length::Pixels(1)));
+        assert_eq!(position("2"), Some(Length::Pixels(2)));
+        assert_eq!(position("20px"), Some(Length::Pixels(20)));
+        assert_eq!(position("-20"), Some(Length::FromEnd(20)));
+        assert_eq!(position("12.5%"), Some(Length::Percent(12.5)));
+        for invalid in &[
+            "-0.5", "-50%", "NaN", "nan.", "inf.0", "1.5px", "0.5.5", "x",
+        ] {
+            assert_eq!(position(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn font_sizes() {
+        let size = |s, dpi| parse_font_size("text", s, dpi).ok();
+        assert_eq!(
+            size("12pt", Some(&"300")),
+            Some(FontSize::Points(12.0, 300.0))
+        );
+        assert_eq!(size("24px", Some(&"0")), Some(FontSize::Pixels(24.0)));
+        for dpi in &["0", "-72", "nan", "inf", "x"] {
+            assert_eq!(size("12pt", Some(dpi)), None, "{}", dpi);
+        }
+        assert_eq!(size("-5px", None), None);
+    }
+}
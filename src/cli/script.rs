@@ -0,0 +1,304 @@
+use crate::error::ImageneError;
+use std::fs;
+
+/// Read a pipeline script and turn every line into the command line argument it stands for,
+/// together with its line number.
+///
+/// Every line is either a flag, a command line argument such as `resize:800,0`, or an action
+/// name followed by its values separated by whitespace. Values can be given in order or by name
+/// as in `resize width=800 height=0`. Values are quoted with `"` and `#` starts a comment.
+pub fn read(path: &str) -> Result<Vec<(usize, String)>, ImageneError> {
+    let source =
+        fs::read_to_string(path).map_err(|e| ImageneError::Parse(format!("{}: {}", path, e)))?;
+
+    let mut arguments = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let at_line = |msg: String| ImageneError::Parse(format!("{}:{}: {}", path, i + 1, msg));
+        let words = split_words(line).map_err(at_line)?;
+        if words.is_empty() {
+            continue;
+        }
+        arguments.push((i + 1, to_argument(&words).map_err(at_line)?));
+    }
+    Ok(arguments)
+}
+
+/// Values of each action in command line order. Names inside parenthesis are joined into the
+/// same `(a:b)` sub category that the command line uses. Values ending in `?` can be left out
+/// even when later ones are given, since the action tells them apart without their position.
+fn parameters(action: &str) -> Option<&'static [&'static str]> {
+    Some(match action {
+        "invert" | "contrast" | "brightness" | "blur" => &["value"],
+        "flip" => &["orientation"],
+        "rotate" => &["angle", "canvas?", "(color)?"],
+        "unsharpen" => &["sigma", "threshold"],
+        "resize" => &["width", "height"],
+        "crop" => &["x", "y", "width", "height"],
+        "pad" => &["top", "right", "bottom", "left", "(color)"],
+        "border" => &["width", "(color)"],
+        "canvas" => &["width", "height", "gravity", "(color)"],
+        "append" => &["image", "direction"],
+        "overlay" => &["image", "x", "y", "opacity", "mode"],
+        "grid" => &["columns", "spacing", "(color)", "images..."],
+        "format" => &["format", "quality"],
+        "metadata" => &["mode"],
+        "filter" => &["filter"],
+        "script" => &["file"],
+        "watermark" | "write" | "text" => &["text", "(x:y)", "(color)", "(font:scale)"],
+        _ => return None,
+    })
+}
+
+fn to_argument(words: &[String]) -> Result<String, String> {
+    let action = &words[0];
+    if words.len() == 1 {
+        // Either a flag or an argument written exactly like on the command line
+        return Ok(action.to_owned());
+    }
+    let names = parameters(action).ok_or_else(|| format!("{}: action not found", action))?;
+
+    // Values are joined with commas below, so commas inside of them are escaped
+    let escaped: Vec<String> = words[1..].iter().map(|w| w.replace(',', "\\,")).collect();
+    let mut positional = Vec::new();
+    let mut named = Vec::new();
+    for word in &escaped {
+        match word.find('=') {
+            Some(i) => named.push((&word[..i], &word[i + 1..])),
+            None if named.is_empty() => positional.push(word.as_str()),
+            None => {
+                return Err(format!(
+                    "{}: value {} given after named values",
+                    action, word
+                ))
+            }
+        }
+    }
+    // A last name ending in ... takes every remaining value
+    let variadic = names.last().map_or(false, |name| name.ends_with("..."));
+    if positional.len() > names.len() && !variadic {
+        return Err(format!("{}: too many values", action));
+    }
+
+    let mut values: Vec<String> = positional.iter().map(|v| (*v).to_owned()).collect();
+    let mut left_out = None;
+    for template in names.iter().skip(positional.len()) {
+        let (template, anywhere) = match template.strip_suffix('?') {
+            Some(template) => (template, true),
+            None => (*template, false),
+        };
+        let parts = split_template(template);
+        let given = parts.iter().any(|part| match part {
+            Part::Name(name) => named.iter().any(|(n, _)| n == name),
+            Part::Literal(_) => false,
+        });
+        // Optional values such as the quality of format can be left out
+        if !given {
+            if anywhere {
+                continue;
+            }
+            left_out = Some(template);
+            break;
+        }
+        let mut value = String::new();
+        for part in parts {
+            match part {
+                Part::Literal(c) => value.push(c),
+                Part::Name(name) => {
+                    let i = named
+                        .iter()
+                        .position(|(n, _)| *n == name)
+                        .ok_or_else(|| format!("{}: missing value for {}", action, name))?;
+                    value.push_str(named.remove(i).1);
+                }
+            }
+        }
+        values.push(value);
+    }
+    // Values are in order, so the ones after a value that was left out can't be given either
+    if let Some(left_out) = left_out {
+        let later = names
+            .iter()
+            .flat_map(|template| split_template(template.trim_end_matches('?')));
+        for part in later {
+            if let Part::Name(name) = part {
+                if named.iter().any(|(n, _)| *n == name) {
+                    return Err(format!(
+                        "{}: {} can't be given without {}",
+                        action, name, left_out
+                    ));
+                }
+            }
+        }
+    }
+    // Named values that aren't part of the positional values are options such as `filter=...`
+    values.extend(named.iter().map(|(n, v)| format!("{}={}", n, v)));
+
+    Ok(format!("{}:{}", action, values.join(",")))
+}
+
+enum Part<'a> {
+    Literal(char),
+    Name(&'a str),
+}
+
+fn split_template(template: &str) -> Vec<Part<'_>> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (i, c) in template.char_indices() {
+        if c == '(' || c == ')' || c == ':' {
+            if start < i {
+                parts.push(Part::Name(&template[start..i]));
+            }
+            parts.push(Part::Literal(c));
+            start = i + 1;
+        }
+    }
+    if start < template.len() {
+        parts.push(Part::Name(&template[start..]));
+    }
+    parts
+}
+
+/// Split a line on whitespace, keeping quoted strings together and dropping comments
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            '\\' if quoted => match chars.next() {
+                Some('n') => word.push('\n'),
+                Some(c) => word.push(c),
+                None => return Err("line ends with \\".to_owned()),
+            },
+            '#' if !quoted && !in_word => break,
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(word.clone());
+                    word.clear();
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quoted {
+        return Err("missing closing \"".to_owned());
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argument(line: &str) -> Result<String, String> {
+        to_argument(&split_words(line)?)
+    }
+
+    #[test]
+    fn words() {
+        let words = |line| split_words(line).unwrap();
+        assert_eq!(words("  resize\t800   0  "), ["resize", "800", "0"]);
+        assert_eq!(words("blur 2 # a comment"), ["blur", "2"]);
+        assert_eq!(words("# only a comment"), Vec::<String>::new());
+        assert_eq!(
+            words(r#"text "Hello, world" x="0.1""#),
+            ["text", "Hello, world", "x=0.1"]
+        );
+        assert_eq!(words(r#"text "a \"b\"\nc""#), ["text", "a \"b\"\nc"]);
+        assert_eq!(words(r##"text "#1" a#b"##), ["text", "#1", "a#b"]);
+        assert!(split_words(r#"text "Hello"#).is_err());
+        assert!(split_words(r#"text "Hello\"#).is_err());
+    }
+
+    #[test]
+    fn positional_and_named() {
+        assert_eq!(argument("--dry-run").unwrap(), "--dry-run");
+        assert_eq!(argument("resize:800,0").unwrap(), "resize:800,0");
+        assert_eq!(argument("resize 800 0").unwrap(), "resize:800,0");
+        assert_eq!(
+            argument("resize width=800 height=0").unwrap(),
+            "resize:800,0"
+        );
+        assert_eq!(
+            argument("resize height=0 width=800").unwrap(),
+            "resize:800,0"
+        );
+        assert_eq!(
+            argument("resize 800 height=0 mode=fit").unwrap(),
+            "resize:800,0,mode=fit"
+        );
+        assert_eq!(
+            argument("pad 1 2 3 4 color=1:0:0:1").unwrap(),
+            "pad:1,2,3,4,(1:0:0:1)"
+        );
+        assert_eq!(argument("format jpg").unwrap(), "format:jpg");
+        assert_eq!(
+            argument("grid 2 4 0:0:0:1 a.png b.png").unwrap(),
+            "grid:2,4,0:0:0:1,a.png,b.png"
+        );
+        assert_eq!(argument("script other.txt").unwrap(), "script:other.txt");
+    }
+
+    #[test]
+    fn optional_values() {
+        assert_eq!(
+            argument(r#"text "Hi" x=0.1 y=0.1 color=1:1:1:1 anchor=center"#).unwrap(),
+            "text:Hi,(0.1:0.1),(1:1:1:1),anchor=center"
+        );
+        assert_eq!(
+            argument(r#"text "Hi" x=0.1 y=0.1 color=1:1:1:1 font="" scale=2"#).unwrap(),
+            "text:Hi,(0.1:0.1),(1:1:1:1),(:2)"
+        );
+        assert_eq!(
+            argument("rotate 10 color=1:1:1:1").unwrap(),
+            "rotate:10,(1:1:1:1)"
+        );
+        assert_eq!(argument("rotate 10 canvas=crop").unwrap(), "rotate:10,crop");
+        assert_eq!(
+            argument("overlay logo.png 0 0 opacity=0.5").unwrap(),
+            "overlay:logo.png,0,0,0.5"
+        );
+    }
+
+    #[test]
+    fn commas_are_escaped() {
+        assert_eq!(
+            argument(r#"text "Hello, world" 0.1:0.1 1:1:1:1"#).unwrap(),
+            "text:Hello\\, world,0.1:0.1,1:1:1:1"
+        );
+    }
+
+    #[test]
+    fn mistakes() {
+        assert!(argument("shrink 5")
+            .unwrap_err()
+            .contains("action not found"));
+        assert!(argument("blur 1 2")
+            .unwrap_err()
+            .contains("too many values"));
+        assert!(argument("resize width=800 0")
+            .unwrap_err()
+            .contains("after named values"));
+        assert!(argument(r#"text "Hi" x=0.1 color=1:1:1:1"#)
+            .unwrap_err()
+            .contains("missing value for y"));
+        assert!(argument("overlay logo.png 0 0 mode=multiply")
+            .unwrap_err()
+            .contains("mode can't be given without opacity"));
+    }
+}
@@ -0,0 +1,76 @@
+use image::ImageError;
+use std::{error, fmt, io};
+
+/// Everything that can go wrong while parsing arguments, loading images, applying actions or
+/// writing the result.
+#[derive(Debug)]
+pub enum ImageneError {
+    /// An argument on the command line couldn't be understood
+    Parse(String),
+    /// An image that the pipeline refers to doesn't exist
+    MissingInput(String),
+    /// An image exists but couldn't be decoded
+    Decode(String, ImageError),
+    /// The resulting image couldn't be encoded or written
+    Encode(String, ImageError),
+    /// A font couldn't be read or isn't a valid font, with the font name and reason
+    Font(String, String),
+    /// An action was given dimensions or coordinates that don't fit the image, with where the
+    /// offending action was given, such as `argument 3` or `script.txt:7`
+    Geometry(String, String),
+}
+
+impl ImageneError {
+    /// The exit code used by the binary for this error
+    ///
+    /// | Code | Meaning             |
+    /// |------|---------------------|
+    /// | 2    | Invalid arguments   |
+    /// | 3    | Missing input image |
+    /// | 4    | Failed to decode    |
+    /// | 5    | Failed to encode    |
+    /// | 6    | Font error          |
+    /// | 7    | Invalid geometry    |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ImageneError::Parse(_) => 2,
+            ImageneError::MissingInput(_) => 3,
+            ImageneError::Decode(_, _) => 4,
+            ImageneError::Encode(_, _) => 5,
+            ImageneError::Font(_, _) => 6,
+            ImageneError::Geometry(_, _) => 7,
+        }
+    }
+
+    /// Classify a failure to open an image, where a file that doesn't exist is a missing input
+    pub(crate) fn open(name: &str, e: ImageError) -> Self {
+        match e {
+            ImageError::IoError(ref io) if io.kind() == io::ErrorKind::NotFound => {
+                ImageneError::MissingInput(name.to_owned())
+            }
+            e => ImageneError::Decode(name.to_owned(), e),
+        }
+    }
+}
+
+impl fmt::Display for ImageneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageneError::Parse(msg) => write!(f, "{}", msg),
+            ImageneError::MissingInput(name) => write!(f, "{}: image not found", name),
+            ImageneError::Decode(name, e) => write!(f, "Failed to load image {}: {}", name, e),
+            ImageneError::Encode(name, e) => write!(f, "Failed to save image {}: {}", name, e),
+            ImageneError::Font(name, e) => write!(f, "loading {}: {}", name, e),
+            ImageneError::Geometry(origin, msg) => write!(f, "{}: {}", origin, msg),
+        }
+    }
+}
+
+impl error::Error for ImageneError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ImageneError::Decode(_, e) | ImageneError::Encode(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
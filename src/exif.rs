@@ -0,0 +1,236 @@
+//! Just enough EXIF parsing to find the tags imagene cares about, without decoding the image.
+
+use crate::action::{Action, Direction, Orientation};
+use crate::metadata;
+
+const ORIENTATION: u16 = 0x0112;
+/// Points to the IFD with the tags about how the photo was taken
+const EXIF_IFD: u16 = 0x8769;
+
+/// Tags that can be read by name, and whether they're in the EXIF IFD instead of the first one
+const TAGS: &[(&str, u16, bool)] = &[
+    ("ImageDescription", 0x010e, false),
+    ("Make", 0x010f, false),
+    ("Model", 0x0110, false),
+    ("Orientation", ORIENTATION, false),
+    ("Software", 0x0131, false),
+    ("DateTime", 0x0132, false),
+    ("Artist", 0x013b, false),
+    ("Copyright", 0x8298, false),
+    ("ExposureTime", 0x829a, true),
+    ("FNumber", 0x829d, true),
+    ("ISOSpeedRatings", 0x8827, true),
+    ("DateTimeOriginal", 0x9003, true),
+    ("DateTimeDigitized", 0x9004, true),
+    ("FocalLength", 0x920a, true),
+    ("BodySerialNumber", 0xa431, true),
+    ("LensMake", 0xa433, true),
+    ("LensModel", 0xa434, true),
+];
+
+/// Find the raw EXIF data, which is structured like a TIFF file, inside of an encoded JPEG, PNG
+/// or TIFF image
+pub fn find(bytes: &[u8]) -> Option<&[u8]> {
+    if bytes.starts_with(&[0xff, 0xd8]) {
+        metadata::jpeg_segments(bytes)
+            .find(|(marker, segment)| *marker == 0xe1 && segment.starts_with(b"Exif\0\0"))
+            .map(|(_, segment)| &segment[6..])
+    } else if bytes.starts_with(metadata::PNG_SIGNATURE) {
+        metadata::png_chunks(bytes)
+            .find(|(kind, _)| *kind == b"eXIf")
+            .map(|(_, data)| data)
+    } else if bytes.starts_with(b"II*\0") || bytes.starts_with(b"MM\0*") {
+        Some(bytes)
+    } else {
+        None
+    }
+}
+
+/// The EXIF orientation of an encoded image, between 1 and 8
+pub fn orientation(bytes: &[u8]) -> Option<u16> {
+    let tiff = Tiff::new(find(bytes)?)?;
+    tiff.entry(tiff.first_ifd()?, ORIENTATION)
+        .and_then(|(_, _, value)| tiff.u16(value))
+        .filter(|o| (1..=8).contains(o))
+}
+
+/// The value of a tag such as `DateTimeOriginal` in raw EXIF data, formatted as text. Numbers
+/// are written out and fractions below one stay fractions, like an exposure time of `1/250`.
+pub fn field(exif: &[u8], name: &str) -> Option<String> {
+    let (_, tag, in_exif_ifd) = TAGS.iter().find(|(n, _, _)| *n == name)?;
+    let tiff = Tiff::new(exif)?;
+    let mut ifd = tiff.first_ifd()?;
+    if *in_exif_ifd {
+        let (_, _, value) = tiff.entry(ifd, EXIF_IFD)?;
+        ifd = tiff.u32(value)? as usize;
+    }
+    let (kind, count, value) = tiff.entry(ifd, *tag)?;
+    let size: usize = match kind {
+        2 => 1,
+        3 => 2,
+        4 => 4,
+        5 => 8,
+        _ => return None,
+    };
+    // Numbers are read from the first value, so there has to be one
+    if kind != 2 && count == 0 {
+        return None;
+    }
+    // Values that don't fit into the entry are stored elsewhere
+    let length = size.checked_mul(count as usize)?;
+    let at = if length > 4 {
+        tiff.u32(value)? as usize
+    } else {
+        value
+    };
+    let data = tiff.data.get(at..at.checked_add(length)?)?;
+
+    Some(match kind {
+        2 => String::from_utf8_lossy(data)
+            .trim_end_matches('\0')
+            .trim()
+            .to_owned(),
+        3 => tiff.u16(at)?.to_string(),
+        4 => tiff.u32(at)?.to_string(),
+        _ => match (tiff.u32(at)?, tiff.u32(at + 4)?) {
+            (n, 0) => n.to_string(),
+            (n, d) if n < d && n > 0 && d % n == 0 => format!("1/{}", d / n),
+            (n, d) => {
+                let value = format!("{:.2}", f64::from(n) / f64::from(d));
+                value.trim_end_matches('0').trim_end_matches('.').to_owned()
+            }
+        },
+    })
+}
+
+/// Mark raw EXIF data as upright, for when the pixels have been turned according to it already
+pub fn reset_orientation(exif: &mut [u8]) {
+    let value = Tiff::new(exif).and_then(|tiff| {
+        let (_, _, value) = tiff.entry(tiff.first_ifd()?, ORIENTATION)?;
+        Some((value, tiff.big_endian))
+    });
+    if let Some((at, big_endian)) = value {
+        let one = if big_endian {
+            1u16.to_be_bytes()
+        } else {
+            1u16.to_le_bytes()
+        };
+        exif[at..at + 2].copy_from_slice(&one);
+    }
+}
+
+/// Whether an orientation swaps the width and height of an image
+pub fn is_transposed(orientation: u16) -> bool {
+    orientation >= 5
+}
+
+/// The actions that turn an image stored with an EXIF orientation upright
+pub fn orientation_actions(orientation: u16) -> Vec<Action> {
+    match orientation {
+        2 => vec![Action::Flip(Orientation::Horizontal)],
+        3 => vec![Action::Rotate(Direction::Down)],
+        4 => vec![Action::Flip(Orientation::Vertical)],
+        5 => vec![
+            Action::Rotate(Direction::Right),
+            Action::Flip(Orientation::Horizontal),
+        ],
+        6 => vec![Action::Rotate(Direction::Right)],
+        7 => vec![
+            Action::Rotate(Direction::Right),
+            Action::Flip(Orientation::Vertical),
+        ],
+        8 => vec![Action::Rotate(Direction::Left)],
+        _ => Vec::new(),
+    }
+}
+
+/// A view into TIFF structured data
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(0..2)? {
+            b"MM" => true,
+            b"II" => false,
+            _ => return None,
+        };
+        Some(Tiff { data, big_endian })
+    }
+
+    /// The 2 bytes at `at`, or `None` past the end of the data
+    fn u16(&self, at: usize) -> Option<u16> {
+        let b = self.data.get(at..at.checked_add(2)?)?;
+        let b = [b[0], b[1]];
+        Some(if self.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    }
+
+    /// The 4 bytes at `at`, or `None` past the end of the data
+    fn u32(&self, at: usize) -> Option<u32> {
+        let b = self.data.get(at..at.checked_add(4)?)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Some(if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    }
+
+    fn first_ifd(&self) -> Option<usize> {
+        self.u32(4).map(|ifd| ifd as usize)
+    }
+
+    /// Find a tag in an IFD, returning its type, count and the offset of its value field
+    fn entry(&self, ifd: usize, tag: u16) -> Option<(u16, u32, usize)> {
+        let count = usize::from(self.u16(ifd)?);
+        let entry = (0..count)
+            .map(|i| ifd + 2 + i * 12)
+            .take_while(|entry| entry + 12 <= self.data.len())
+            .find(|entry| self.u16(*entry) == Some(tag))?;
+        Some((self.u16(entry + 2)?, self.u32(entry + 4)?, entry + 8))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A little endian TIFF header, an IFD with the make and a pointer to the EXIF IFD, and an
+    /// EXIF IFD with an f-number of 28/10, or with no value at all when it's `empty`
+    fn exif(empty: bool) -> Vec<u8> {
+        let mut exif = b"II*\0\x08\0\0\0\x02\0".to_vec();
+        exif.extend(b"\x0f\x01\x02\0\x04\0\0\0Foo\0");
+        exif.extend(b"\x69\x87\x04\0\x01\0\0\0\x26\0\0\0\0\0\0\0");
+        if empty {
+            exif.extend(b"\x01\0\x9d\x82\x05\0\0\0\0\0\0\0\0\0");
+        } else {
+            exif.extend(b"\x01\0\x9d\x82\x05\0\x01\0\0\0\x38\0\0\0\0\0\0\0");
+            exif.extend(b"\x1c\0\0\0\x0a\0\0\0");
+        }
+        exif
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(field(&exif(false), "Make"), Some("Foo".to_owned()));
+        assert_eq!(field(&exif(false), "FNumber"), Some("2.8".to_owned()));
+        assert_eq!(field(&exif(false), "Model"), None);
+        assert_eq!(field(&exif(true), "FNumber"), None);
+    }
+
+    #[test]
+    fn truncated() {
+        let exif = exif(false);
+        for end in 0..exif.len() {
+            field(&exif[..end], "Make");
+            field(&exif[..end], "FNumber");
+            orientation(&exif[..end]);
+        }
+    }
+}
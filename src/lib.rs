@@ -0,0 +1,52 @@
+//! Imagene as a library.
+//!
+//! The same actions that the `imagene` binary parses from its command line can be built
+//! programmatically through a [`Pipeline`](struct.Pipeline.html) and applied to an in-memory
+//! `DynamicImage`.
+//!
+//! ```no_run
+//! use imagene::{Action, Direction, Pipeline};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let image = image::open("in.png")?;
+//! let image = Pipeline::new()
+//!     .action(Action::Contrast(20.0))
+//!     .action(Action::Rotate(Direction::Right))
+//!     .run(image)?;
+//! image.save("out.png")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Actions with many values name them, and take the same types the command line is parsed into:
+//!
+//! ```
+//! use imagene::{Action, FontSize, Length, TextEffects, TextLayout};
+//!
+//! let caption = Action::Watermark {
+//!     text: "{filename}".to_owned(),
+//!     position: (Length::Pixels(10), Length::FromEnd(10)),
+//!     color: (1.0, 1.0, 1.0, 1.0),
+//!     font: (String::new(), FontSize::Pixels(24.0)),
+//!     layout: TextLayout::default(),
+//!     effects: TextEffects::default(),
+//!     tile: None,
+//! };
+//! ```
+
+extern crate image;
+
+pub mod action;
+pub mod batch;
+pub mod cli;
+mod error;
+mod exif;
+pub mod metadata;
+mod pipeline;
+
+pub use action::{
+    Action, Align, Blend, Canvas, Direction, Flag, FontSize, Gravity, Length, Metadata,
+    Orientation, ResizeMode, Shadow, TextEffects, TextLayout, Tile,
+};
+pub use error::ImageneError;
+pub use pipeline::{Images, Pipeline, Step};
@@ -1,74 +1,137 @@
 extern crate image;
+extern crate imagene;
 
-mod action;
-mod cli;
-use image::*;
-use std::collections::HashMap;
-use std::fs::File;
+use imagene::{batch, cli, Flag, ImageneError, Images, Pipeline};
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
 use std::process::exit;
-use std::sync::mpsc;
 
 fn main() {
-    let (io, settings, image_names) = cli::parse();
+    let args: Vec<String> = env::args().collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        exit(e.exit_code());
+    }
+}
 
-    // Load images
-    let mut images: HashMap<String, mpsc::Receiver<DynamicImage>> = HashMap::new();
-    for image_name in image_names {
-        if images.contains_key(&image_name) {
-            continue;
+fn run(args: &[String]) -> Result<(), ImageneError> {
+    let (io, settings, image_names) = match cli::parse(args)? {
+        Some(parsed) => parsed,
+        None => {
+            println!("{}", cli::help_message());
+            return Ok(());
         }
-        let (s, r) = mpsc::channel();
-        let i_n = image_name.clone();
-        std::thread::spawn(move || {
-            s.send(
-                image::open(&i_n)
-                    .map_err(|e| {
-                        eprintln!("{}", e);
-                    })
-                    .unwrap_or_else(|_| {
-                        eprintln!("Aborting because one or more errors while loading image");
-                        exit(2)
-                    }),
-            )
-            .unwrap();
-        });
-        images.insert(image_name, r);
+    };
+    let dry_run = cli::flag_is_enabled(settings.flags.get(&Flag::DryRun));
+    if cli::flag_is_enabled(settings.flags.get(&Flag::Batch)) {
+        return run_batch(&io, Pipeline::from(settings), image_names, dry_run);
+    }
+    if dry_run {
+        return explain(&io, Pipeline::from(settings), image_names);
     }
+    if io.1 != "stdout" {
+        println!("Using infile {} and outfile {}", io.0, io.1);
+    };
 
     // Use extension of outfile as default, can be overwritten with format: action
-    let outname = io.1.clone().to_owned();
-    let gutted_outname: Vec<&str> = outname.split(".").collect();
-    let out_format = match gutted_outname[gutted_outname.len() - 1] {
-        "png" => ImageOutputFormat::PNG,
-        "jpg" => ImageOutputFormat::JPEG(100),
-        "jpeg" => ImageOutputFormat::JPEG(100),
-        "bmp" => ImageOutputFormat::BMP,
-        "gif" => ImageOutputFormat::GIF,
-        "ico" => ImageOutputFormat::ICO,
-        &_ => ImageOutputFormat::PNG,
-    };
+    let pipeline = Pipeline::from(settings).format(cli::output_format(&io.1));
+
+    // Load images
+    let auto_orient = !pipeline.flag_is_enabled(&Flag::NoAutoOrient);
+    let mut images = load(&io.0, image_names, auto_orient)?;
+    // Mistakes in the actions are reported from the image headers, before decoding any pixels
+    let dimensions = images.dimensions(&io.0)?;
+    pipeline.validate(&io.0, dimensions, &mut images)?;
+    images.preload();
+    let metadata = pipeline.metadata_from(&io.0, &images)?;
+    let image = images.take(&io.0)?;
+
+    let (image, out_format) = pipeline.run_with(&io.0, image, &mut images)?;
+
+    let mut encoded = Vec::new();
+    image
+        .write_to(&mut encoded, out_format)
+        .map(|_| metadata.embed(encoded))
+        .and_then(|encoded| match io.1.as_ref() {
+            "stdout" => std::io::stdout().write_all(&encoded).map_err(Into::into),
+            _ => fs::write(&io.1, encoded).map_err(Into::into),
+        })
+        .map_err(|e| ImageneError::Encode(io.1.clone(), e))
+}
+
+/// Refer to every image without decoding any of them yet, reading the infile from stdin if asked to
+fn load(infile: &str, image_names: Vec<String>, auto_orient: bool) -> Result<Images, ImageneError> {
+    if !cli::is_stdin(infile) {
+        return Ok(Images::lazy(image_names, auto_orient));
+    }
+    let mut images = Images::lazy(image_names.into_iter().filter(|n| n != infile), auto_orient);
+    let mut bytes = Vec::new();
+    std::io::stdin()
+        .read_to_end(&mut bytes)
+        .map_err(|e| ImageneError::Decode(infile.to_owned(), e.into()))?;
+    images.insert_encoded(infile.to_owned(), bytes);
+    Ok(images)
+}
+
+/// Apply the pipeline to every file matching the infile pattern
+fn run_batch(
+    io: &(String, String),
+    pipeline: Pipeline,
+    image_names: Vec<String>,
+    dry_run: bool,
+) -> Result<(), ImageneError> {
+    let files = batch::expand(&io.0)?;
+    // The first image name is the pattern itself
+    let extra = &image_names[1..];
+
+    if dry_run {
+        let outnames = batch::unique_output_names(&io.1, &files)?;
+        for (file, outname) in files.into_iter().zip(outnames) {
+            let mut names = vec![file.clone()];
+            names.extend(extra.iter().cloned());
+            explain(&(file, outname), pipeline.clone(), names)?;
+        }
+        return Ok(());
+    }
+
+    let mut failure = None;
+    for (file, result) in batch::run(&pipeline, files, &io.1, extra)? {
+        match result {
+            Ok(outname) => println!("{} -> {}", file, outname),
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                failure = failure.or(Some(e));
+            }
+        }
+    }
+    failure.map_or(Ok(()), Err)
+}
 
-    let (image, out_format) =
-        action::apply_actions(&io.0, out_format, settings.actions, settings.flags, images);
+/// Print every action with the dimensions and format it results in, reading only image headers
+fn explain(
+    io: &(String, String),
+    pipeline: Pipeline,
+    image_names: Vec<String>,
+) -> Result<(), ImageneError> {
+    let pipeline = pipeline.format(cli::output_format(&io.1));
+    let auto_orient = !pipeline.flag_is_enabled(&Flag::NoAutoOrient);
+    let mut images = load(&io.0, image_names, auto_orient)?;
+    let (w, h) = images.dimensions(&io.0)?;
 
-    match io.1.as_ref() {
-        "stdout" => image
-            .write_to(&mut std::io::stdout(), out_format)
-            .unwrap_or_else(|e| {
-                eprintln!("Failed to save image: {}", e);
-                exit(2)
-            }),
-        _ => image
-            .write_to(
-                &mut File::create(&io.1).unwrap_or_else(|_| {
-                    eprintln!("Outfile {} not found", io.1);
-                    exit(2)
-                }),
-                out_format,
-            )
-            .unwrap_or_else(|e| {
-                eprintln!("Failed to save image: {}", e);
-                exit(2)
-            }),
+    println!("{} ({}x{})", io.0, w, h);
+    // Steps are printed as they're predicted, so the ones before a failing action are still shown
+    for (i, step) in pipeline.plan(&io.0, (w, h), &mut images).enumerate() {
+        let step = step?;
+        println!(
+            "{:>3}. {:<40} -> {}x{} {:?}",
+            i + 1,
+            format!("{:?}", step.action),
+            step.dimensions.0,
+            step.dimensions.1,
+            step.format
+        );
     }
+    println!("{}", io.1);
+    Ok(())
 }
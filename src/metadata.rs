@@ -0,0 +1,307 @@
+//! Carrying EXIF, XMP and ICC metadata from an input image over to JPEG and PNG outputs.
+
+use crate::action::Metadata;
+use crate::exif;
+
+const XMP_JPEG: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_PNG: &[u8] = b"XML:com.adobe.xmp";
+const ICC_JPEG: &[u8] = b"ICC_PROFILE\0";
+pub(crate) const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// The metadata chunks of an image, stored the same way regardless of the format they came from
+#[derive(Clone, Default, Debug)]
+pub struct Chunks {
+    /// Raw TIFF structured EXIF data
+    pub exif: Option<Vec<u8>>,
+    /// XMP packet
+    pub xmp: Option<Vec<u8>>,
+    /// Uncompressed ICC profile
+    pub icc: Option<Vec<u8>>,
+}
+
+impl Chunks {
+    /// Read the metadata of an encoded JPEG or PNG image. Other formats have no metadata.
+    pub fn read(bytes: &[u8]) -> Chunks {
+        let mut chunks = Chunks {
+            exif: exif::find(bytes).map(|exif| exif.to_vec()),
+            ..Chunks::default()
+        };
+        if bytes.starts_with(&[0xff, 0xd8]) {
+            chunks.read_jpeg(bytes);
+        } else if bytes.starts_with(PNG_SIGNATURE) {
+            chunks.read_png(bytes);
+        }
+        chunks
+    }
+
+    fn read_jpeg(&mut self, bytes: &[u8]) {
+        let mut icc: Vec<(u8, &[u8])> = Vec::new();
+        for (marker, segment) in jpeg_segments(bytes) {
+            if marker == 0xe1 && segment.starts_with(XMP_JPEG) {
+                self.xmp = Some(segment[XMP_JPEG.len()..].to_vec());
+            }
+            // Profiles are split over numbered segments since a segment holds at most 64KB
+            if marker == 0xe2 && segment.starts_with(ICC_JPEG) && segment.len() > 14 {
+                icc.push((segment[12], &segment[14..]));
+            }
+        }
+        if !icc.is_empty() {
+            icc.sort_by_key(|(i, _)| *i);
+            self.icc = Some(
+                icc.iter()
+                    .flat_map(|(_, data)| data.iter().cloned())
+                    .collect(),
+            );
+        }
+    }
+
+    fn read_png(&mut self, bytes: &[u8]) {
+        for (kind, data) in png_chunks(bytes) {
+            match kind {
+                b"iCCP" => {
+                    // Profile name, null separator and compression method before the profile
+                    let start = data.iter().position(|b| *b == 0).map(|i| i + 2);
+                    self.icc = start
+                        .and_then(|i| data.get(i..))
+                        .and_then(|profile| inflate::inflate_bytes_zlib(profile).ok());
+                }
+                b"iTXt" if data.starts_with(XMP_PNG) && data.get(XMP_PNG.len()) == Some(&0) => {
+                    // Keyword, compression flag and method, then a language tag and translated
+                    // keyword which are both null terminated
+                    let compressed = data.get(XMP_PNG.len() + 1);
+                    let text = data.get(XMP_PNG.len() + 3..).unwrap_or(&[]);
+                    let mut nulls = text.iter().enumerate().filter(|(_, b)| **b == 0);
+                    if let (Some(_), Some((i, _))) = (nulls.next(), nulls.next()) {
+                        if compressed == Some(&0) {
+                            self.xmp = Some(text[i + 1..].to_vec());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Only keep what `policy` asks for
+    pub fn filter(self, policy: &Metadata) -> Chunks {
+        match policy {
+            Metadata::Keep => self,
+            Metadata::IccOnly => Chunks {
+                icc: self.icc,
+                ..Chunks::default()
+            },
+            Metadata::Strip => Chunks::default(),
+        }
+    }
+
+    /// Mark the EXIF data as upright, for images that were turned by their EXIF orientation
+    pub fn reset_orientation(&mut self) {
+        if let Some(exif) = self.exif.as_mut() {
+            exif::reset_orientation(exif);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none() && self.icc.is_none()
+    }
+
+    /// Add the metadata to an encoded JPEG or PNG image. Other formats are returned as is.
+    pub fn embed(&self, encoded: Vec<u8>) -> Vec<u8> {
+        if self.is_empty() {
+            encoded
+        } else if encoded.starts_with(&[0xff, 0xd8]) {
+            self.embed_jpeg(encoded)
+        } else if encoded.starts_with(PNG_SIGNATURE) {
+            self.embed_png(encoded)
+        } else {
+            encoded
+        }
+    }
+
+    fn embed_jpeg(&self, encoded: Vec<u8>) -> Vec<u8> {
+        let mut segments = Vec::new();
+        if let Some(ref exif) = self.exif {
+            segments.push(jpeg_segment(0xe1, &[b"Exif\0\0", exif]));
+        }
+        if let Some(ref xmp) = self.xmp {
+            segments.push(jpeg_segment(0xe1, &[XMP_JPEG, xmp]));
+        }
+        if let Some(ref icc) = self.icc {
+            let parts: Vec<&[u8]> = icc.chunks(0xffff - 2 - 14).collect();
+            for (i, part) in parts.iter().enumerate() {
+                let numbers = [i as u8 + 1, parts.len() as u8];
+                segments.push(jpeg_segment(0xe2, &[ICC_JPEG, &numbers, part]));
+            }
+        }
+
+        // Metadata goes right after the JFIF header if there is one, which has to come first
+        let mut at = 2;
+        if let Some((0xe0, segment)) = jpeg_segments(&encoded).next() {
+            at += 4 + segment.len();
+        }
+        splice(encoded, at, segments.concat())
+    }
+
+    fn embed_png(&self, encoded: Vec<u8>) -> Vec<u8> {
+        let mut chunks = Vec::new();
+        if let Some(ref icc) = self.icc {
+            let profile = deflate::deflate_bytes_zlib(icc);
+            chunks.push(png_chunk(b"iCCP", &[b"ICC Profile\0\0", &profile]));
+        }
+        if let Some(ref exif) = self.exif {
+            chunks.push(png_chunk(b"eXIf", &[exif]));
+        }
+        if let Some(ref xmp) = self.xmp {
+            chunks.push(png_chunk(b"iTXt", &[XMP_PNG, b"\0\0\0\0\0", xmp]));
+        }
+
+        // Metadata has to come before the image data, so it goes right after the header
+        let at = match png_chunks(&encoded).next() {
+            Some((b"IHDR", data)) => PNG_SIGNATURE.len() + 12 + data.len(),
+            _ => 0,
+        };
+        if at == 0 {
+            return encoded;
+        }
+        splice(encoded, at, chunks.concat())
+    }
+}
+
+fn splice(mut bytes: Vec<u8>, at: usize, insert: Vec<u8>) -> Vec<u8> {
+    let tail = bytes.split_off(at);
+    bytes.extend(insert);
+    bytes.extend(tail);
+    bytes
+}
+
+/// Iterate over the marker and data of every JPEG segment before the image data
+pub(crate) fn jpeg_segments(bytes: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut i = 2;
+    std::iter::from_fn(move || {
+        if i + 4 > bytes.len() || bytes[i] != 0xff || bytes[i + 1] == 0xda || bytes[i + 1] == 0xd9 {
+            return None;
+        }
+        let marker = bytes[i + 1];
+        let length = usize::from(u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]));
+        let segment = bytes.get(i + 4..i + 2 + length)?;
+        i += 2 + length;
+        Some((marker, segment))
+    })
+}
+
+fn jpeg_segment(marker: u8, parts: &[&[u8]]) -> Vec<u8> {
+    let length = parts.iter().map(|p| p.len()).sum::<usize>() + 2;
+    let mut segment = vec![0xff, marker];
+    segment.extend_from_slice(&(length as u16).to_be_bytes());
+    for part in parts {
+        segment.extend_from_slice(part);
+    }
+    segment
+}
+
+/// Iterate over the type and data of every PNG chunk
+pub(crate) fn png_chunks(bytes: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut i = PNG_SIGNATURE.len();
+    std::iter::from_fn(move || {
+        let length = bytes.get(i..i + 4)?;
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        let kind = bytes.get(i + 4..i + 8)?;
+        let data = bytes.get(i + 8..i + 8 + length)?;
+        // Length, type, data and CRC
+        i += 12 + length;
+        Some((kind, data))
+    })
+}
+
+fn png_chunk(kind: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let length = parts.iter().map(|p| p.len()).sum::<usize>();
+    let mut chunk = Vec::with_capacity(length + 12);
+    chunk.extend_from_slice(&(length as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    for part in parts {
+        chunk.extend_from_slice(part);
+    }
+    let crc = crc32(&chunk[4..]);
+    chunk.extend_from_slice(&crc.to_be_bytes());
+    chunk
+}
+
+/// The CRC-32 that PNG chunks end with
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, b| {
+        (0..8).fold(crc ^ u32::from(*b), |crc, _| {
+            if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+
+    fn encode(format: ImageOutputFormat) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        DynamicImage::new_rgb8(16, 8)
+            .write_to(&mut encoded, format)
+            .unwrap();
+        encoded
+    }
+
+    fn chunks() -> Chunks {
+        // A little endian TIFF header and an IFD holding only an orientation of 6
+        let exif = b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0\x06\0\0\0\0\0\0\0".to_vec();
+        Chunks {
+            exif: Some(exif),
+            xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"></x:xmpmeta>".to_vec()),
+            // Large enough to be split over several JPEG segments
+            icc: Some((0..150_000).map(|i| (i % 251) as u8).collect()),
+        }
+    }
+
+    fn round_trip(format: ImageOutputFormat) {
+        let embedded = chunks().embed(encode(format));
+        let read = Chunks::read(&embedded);
+        assert_eq!(read.exif, chunks().exif);
+        assert_eq!(read.xmp, chunks().xmp);
+        assert_eq!(read.icc, chunks().icc);
+        assert_eq!(exif::orientation(&embedded), Some(6));
+        assert_eq!(image::load_from_memory(&embedded).unwrap().width(), 16);
+    }
+
+    #[test]
+    fn jpeg_round_trip() {
+        round_trip(ImageOutputFormat::JPEG(90));
+    }
+
+    #[test]
+    fn png_round_trip() {
+        round_trip(ImageOutputFormat::PNG);
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+    }
+
+    #[test]
+    fn truncated_png_xmp() {
+        for end in &[&b"\0"[..], b"\0\0", b"\0\0\0", b"\0\0\0\0"] {
+            let mut png = PNG_SIGNATURE.to_vec();
+            png.extend(png_chunk(b"iTXt", &[XMP_PNG, end]));
+            assert_eq!(Chunks::read(&png).xmp, None);
+        }
+    }
+
+    #[test]
+    fn no_metadata() {
+        let encoded = encode(ImageOutputFormat::PNG);
+        assert!(Chunks::read(&encoded).is_empty());
+        assert_eq!(Chunks::default().embed(encoded.clone()), encoded);
+    }
+}
@@ -0,0 +1,423 @@
+use crate::action::{self, Action, Flag, Metadata};
+use crate::cli::{self, Settings};
+use crate::error::ImageneError;
+use crate::exif;
+use crate::metadata::Chunks;
+use image::{
+    DynamicImage, GenericImageView, ImageDecoder, ImageError, ImageFormat, ImageOutputFormat,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Cursor, Read, Seek};
+use std::sync::mpsc;
+
+/// An ordered list of actions together with the flags that affect how they are applied.
+#[derive(Clone)]
+pub struct Pipeline {
+    actions: Vec<Action>,
+    arguments: Vec<String>,
+    flags: HashMap<Flag, bool>,
+    format: ImageOutputFormat,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline {
+            actions: Vec::new(),
+            arguments: Vec::new(),
+            flags: HashMap::new(),
+            format: ImageOutputFormat::PNG,
+        }
+    }
+
+    /// Append an action to the end of the pipeline
+    pub fn action(mut self, action: Action) -> Self {
+        self.arguments
+            .push(format!("action {}", self.actions.len() + 1));
+        self.actions.push(action);
+        self
+    }
+
+    /// Enable a flag for every action in the pipeline
+    pub fn flag(mut self, flag: Flag) -> Self {
+        self.flags.insert(flag, true);
+        self
+    }
+
+    /// Output format used unless a `Format` action overrides it
+    pub fn format(mut self, format: ImageOutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    pub fn flag_is_enabled(&self, flag: &Flag) -> bool {
+        cli::flag_is_enabled(self.flags.get(flag))
+    }
+
+    /// Which metadata is carried over to the output. The last `Metadata` action decides, and
+    /// without one everything is stripped.
+    pub fn metadata(&self) -> Metadata {
+        self.actions
+            .iter()
+            .rev()
+            .find_map(|action| match *action {
+                Action::Metadata(ref metadata) => Some(metadata.clone()),
+                _ => None,
+            })
+            .unwrap_or(Metadata::Strip)
+    }
+
+    /// Read the metadata of the source image `name` that should be carried over to the output,
+    /// either from `images` if it's still encoded there or from disk. Nothing is read when the
+    /// pipeline strips metadata.
+    pub fn metadata_from(&self, name: &str, images: &Images) -> Result<Chunks, ImageneError> {
+        let policy = self.metadata();
+        if policy == Metadata::Strip {
+            return Ok(Chunks::default());
+        }
+        let mut chunks = match images.encoded.get(name) {
+            Some(bytes) => Chunks::read(bytes),
+            None => Chunks::read(
+                &fs::read(name).map_err(|e| ImageneError::open(name, ImageError::IoError(e)))?,
+            ),
+        }
+        .filter(&policy);
+        // The pixels were already turned, so the output must not be turned a second time
+        if !self.flag_is_enabled(&Flag::NoAutoOrient) {
+            chunks.reset_orientation();
+        }
+        Ok(chunks)
+    }
+
+    /// Check that every action fits the image it will be applied to, starting from an image of
+    /// `dimensions`, and return the final dimensions. Only image headers are read.
+    pub fn validate(
+        &self,
+        name: &str,
+        dimensions: (u32, u32),
+        images: &mut Images,
+    ) -> Result<(u32, u32), ImageneError> {
+        self.plan(name, dimensions, images)
+            .try_fold(dimensions, |_, step| step.map(|step| step.dimensions))
+    }
+
+    /// Predict the dimensions and output format after every action, starting from an image of
+    /// `dimensions`. Only image headers are read. Stops after the first action that doesn't fit,
+    /// so the steps before it can still be shown.
+    pub fn plan<'a>(
+        &'a self,
+        name: &'a str,
+        dimensions: (u32, u32),
+        images: &'a mut Images,
+    ) -> impl Iterator<Item = Result<Step<'a>, ImageneError>> + 'a {
+        let mut format = self.format.clone();
+        self.actions.iter().zip(&self.arguments).scan(
+            Some(dimensions),
+            move |dimensions, (action, argument)| {
+                // Nothing can be predicted after an action that failed
+                let before = (*dimensions)?;
+                let after = action::dimensions_after(action, before, name, images)
+                    .map_err(|e| ImageneError::Geometry(argument.to_owned(), e));
+                *dimensions = after.as_ref().ok().cloned();
+                if let Action::Format(ref f) = *action {
+                    format = f.clone();
+                }
+                Some(after.map(|dimensions| Step {
+                    action,
+                    dimensions,
+                    format: format.clone(),
+                }))
+            },
+        )
+    }
+
+    /// Run the pipeline on a single image. Actions that reference other images by name, such as
+    /// `Append`, only work with [`run_with`](#method.run_with).
+    pub fn run(&self, image: DynamicImage) -> Result<DynamicImage, ImageneError> {
+        self.run_with("", image, &mut Images::new())
+            .map(|(image, _)| image)
+    }
+
+    /// Run the pipeline on `image`, resolving extra images by name from `images`. The `name` of
+    /// the source image lets actions refer back to the image being processed.
+    pub fn run_with(
+        &self,
+        name: &str,
+        image: DynamicImage,
+        images: &mut Images,
+    ) -> Result<(DynamicImage, ImageOutputFormat), ImageneError> {
+        self.validate(name, image.dimensions(), images)?;
+        action::apply_actions(
+            name,
+            image,
+            self.format.clone(),
+            &self.actions,
+            &self.arguments,
+            &self.flags,
+            images,
+        )
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline::new()
+    }
+}
+
+impl From<Settings> for Pipeline {
+    fn from(settings: Settings) -> Self {
+        Pipeline {
+            actions: settings.actions,
+            arguments: settings.arguments,
+            flags: settings.flags,
+            format: ImageOutputFormat::PNG,
+        }
+    }
+}
+
+/// The predicted result of a single action in a pipeline
+pub struct Step<'a> {
+    pub action: &'a Action,
+    pub dimensions: (u32, u32),
+    pub format: ImageOutputFormat,
+}
+
+/// Images that actions can refer to by name. Images are either inserted directly, kept encoded in
+/// memory, or loaded from disk on background threads and received the first time they're needed.
+///
+/// Images that are decoded here are turned upright according to their EXIF orientation unless
+/// `auto_orient` is disabled.
+pub struct Images {
+    pending: HashMap<String, mpsc::Receiver<Result<DynamicImage, ImageneError>>>,
+    unloaded: HashSet<String>,
+    encoded: HashMap<String, Vec<u8>>,
+    loaded: HashMap<String, DynamicImage>,
+    /// Raw EXIF data of images that were read for it, kept after the image is decoded
+    exif: HashMap<String, Option<Vec<u8>>>,
+    auto_orient: bool,
+}
+
+impl Images {
+    pub fn new() -> Self {
+        Images {
+            pending: HashMap::new(),
+            unloaded: HashSet::new(),
+            encoded: HashMap::new(),
+            loaded: HashMap::new(),
+            exif: HashMap::new(),
+            auto_orient: true,
+        }
+    }
+
+    /// Refer to every named image on disk without loading any of them until they're needed
+    pub fn lazy<I: IntoIterator<Item = String>>(names: I, auto_orient: bool) -> Self {
+        Images {
+            unloaded: names.into_iter().collect(),
+            auto_orient,
+            ..Images::new()
+        }
+    }
+
+    /// Start loading every named image from disk, one thread per image
+    pub fn load<I: IntoIterator<Item = String>>(names: I, auto_orient: bool) -> Self {
+        let mut images = Images::lazy(names, auto_orient);
+        images.preload();
+        images
+    }
+
+    /// Start loading every image on disk that isn't loaded yet, one thread per image
+    pub fn preload(&mut self) {
+        let auto_orient = self.auto_orient;
+        for name in self.unloaded.drain() {
+            let (s, r) = mpsc::channel();
+            let n = name.clone();
+            std::thread::spawn(move || {
+                // The receiver is gone if the pipeline already failed, so there's nobody to tell
+                let _ = s.send(open(&n, auto_orient));
+            });
+            self.pending.insert(name, r);
+        }
+    }
+
+    pub fn insert(&mut self, name: String, image: DynamicImage) {
+        self.forget(&name);
+        self.loaded.insert(name, image);
+    }
+
+    /// Insert an image that is still encoded, such as one read from stdin. The format is detected
+    /// from its magic bytes once it's needed.
+    pub fn insert_encoded(&mut self, name: String, bytes: Vec<u8>) {
+        self.forget(&name);
+        self.encoded.insert(name, bytes);
+    }
+
+    /// Get an image by name, waiting for it to finish loading if necessary
+    pub fn get(&mut self, name: &str) -> Result<&DynamicImage, ImageneError> {
+        if let Some(image) = self.decode(name) {
+            self.loaded.insert(name.to_owned(), image?);
+        }
+        self.loaded
+            .get(name)
+            .ok_or_else(|| ImageneError::MissingInput(name.to_owned()))
+    }
+
+    /// Remove an image by name, waiting for it to finish loading if necessary
+    pub fn take(&mut self, name: &str) -> Result<DynamicImage, ImageneError> {
+        if let Some(image) = self.decode(name) {
+            return image;
+        }
+        self.loaded
+            .remove(name)
+            .ok_or_else(|| ImageneError::MissingInput(name.to_owned()))
+    }
+
+    /// Decode an image that isn't loaded yet, if there is one by that name
+    fn decode(&mut self, name: &str) -> Option<Result<DynamicImage, ImageneError>> {
+        if let Some(r) = self.pending.remove(name) {
+            return Some(
+                r.recv()
+                    .unwrap_or_else(|_| Err(ImageneError::MissingInput(name.to_owned()))),
+            );
+        }
+        if self.unloaded.remove(name) {
+            return Some(open(name, self.auto_orient));
+        }
+        let auto_orient = self.auto_orient;
+        self.encoded.remove(name).map(|bytes| {
+            // The encoded bytes are gone once decoded, so the EXIF data is kept around
+            self.exif.insert(
+                name.to_owned(),
+                exif::find(&bytes).map(|exif| exif.to_vec()),
+            );
+            let image = image::guess_format(&bytes)
+                .and_then(|format| image::load_from_memory_with_format(&bytes, format))
+                .map_err(|e| ImageneError::Decode(name.to_owned(), e))?;
+            Ok(if auto_orient {
+                orient(image, &bytes)
+            } else {
+                image
+            })
+        })
+    }
+
+    fn forget(&mut self, name: &str) {
+        self.pending.remove(name);
+        self.unloaded.remove(name);
+        self.encoded.remove(name);
+        self.loaded.remove(name);
+        self.exif.remove(name);
+    }
+
+    /// Raw EXIF data of an image by name, read from memory if it's still encoded there or from
+    /// disk otherwise
+    pub fn exif(&mut self, name: &str) -> Option<&[u8]> {
+        if !self.exif.contains_key(name) {
+            let exif = match self.encoded.get(name) {
+                Some(bytes) => exif::find(bytes).map(|exif| exif.to_vec()),
+                None => fs::read(name)
+                    .ok()
+                    .and_then(|bytes| exif::find(&bytes).map(|exif| exif.to_vec())),
+            };
+            self.exif.insert(name.to_owned(), exif);
+        }
+        self.exif.get(name).and_then(|exif| exif.as_deref())
+    }
+
+    /// Dimensions of an image by name. Images that haven't finished loading have their
+    /// dimensions read from the file header instead of waiting for them to be decoded.
+    pub fn dimensions(&mut self, name: &str) -> Result<(u32, u32), ImageneError> {
+        if let Some(image) = self.loaded.get(name) {
+            return Ok(image.dimensions());
+        }
+        let (bytes, (w, h)) = if let Some(bytes) = self.encoded.get(name) {
+            let err = |e| ImageneError::Decode(name.to_owned(), e);
+            let dimensions = match header_dimensions(Cursor::new(&bytes[..])).map_err(err)? {
+                Some(dimensions) => dimensions,
+                None => image::load_from_memory(bytes)
+                    .map(|image| image.dimensions())
+                    .map_err(err)?,
+            };
+            (bytes.to_owned(), dimensions)
+        } else if self.pending.contains_key(name) || self.unloaded.contains(name) {
+            read_dimensions(name)?
+        } else {
+            return Err(ImageneError::MissingInput(name.to_owned()));
+        };
+
+        match exif::orientation(&bytes) {
+            Some(o) if self.auto_orient && exif::is_transposed(o) => Ok((h, w)),
+            _ => Ok((w, h)),
+        }
+    }
+}
+
+impl Default for Images {
+    fn default() -> Self {
+        Images::new()
+    }
+}
+
+/// Open an image file, turning it upright according to its EXIF orientation if asked to
+pub fn open(name: &str, auto_orient: bool) -> Result<DynamicImage, ImageneError> {
+    let err = |e| ImageneError::open(name, e);
+    if !auto_orient {
+        return image::open(name).map_err(err);
+    }
+    let bytes = fs::read(name).map_err(|e| err(ImageError::IoError(e)))?;
+    let image = match image::guess_format(&bytes) {
+        Ok(format) => image::load_from_memory_with_format(&bytes, format),
+        // Formats without magic bytes are detected by their extension instead
+        Err(_) => image::open(name),
+    }
+    .map_err(err)?;
+    Ok(orient(image, &bytes))
+}
+
+/// Apply the EXIF orientation found in the encoded `bytes` of an image
+fn orient(image: DynamicImage, bytes: &[u8]) -> DynamicImage {
+    let actions = exif::orientation(bytes)
+        .map(exif::orientation_actions)
+        .unwrap_or_default();
+    actions.iter().fold(image, action::rotate_or_flip)
+}
+
+/// Read the dimensions of an image file from its header without decoding any pixels, together
+/// with the start of the file where its EXIF data is expected
+fn read_dimensions(name: &str) -> Result<(Vec<u8>, (u32, u32)), ImageneError> {
+    let err = |e| ImageneError::open(name, e);
+    let mut head = Vec::new();
+    File::open(name)
+        .and_then(|f| f.take(1 << 17).read_to_end(&mut head))
+        .map_err(|e| err(ImageError::IoError(e)))?;
+
+    let r = BufReader::new(File::open(name).map_err(|e| err(ImageError::IoError(e)))?);
+    let dimensions = match header_dimensions(r).map_err(err)? {
+        Some(dimensions) => dimensions,
+        // Formats without a cheap header reader are decoded in full
+        None => image::open(name)
+            .map(|image| image.dimensions())
+            .map_err(err)?,
+    };
+    Ok((head, dimensions))
+}
+
+/// Read the dimensions from the header of an encoded image, if its format has a header reader
+fn header_dimensions<R: BufRead + Seek>(mut r: R) -> Result<Option<(u32, u32)>, ImageError> {
+    let format = image::guess_format(r.fill_buf()?);
+
+    let (w, h) = match format {
+        Ok(ImageFormat::PNG) => image::png::PNGDecoder::new(r)?.dimensions(),
+        Ok(ImageFormat::JPEG) => image::jpeg::JPEGDecoder::new(r)?.dimensions(),
+        Ok(ImageFormat::GIF) => image::gif::Decoder::new(r)?.dimensions(),
+        Ok(ImageFormat::BMP) => image::bmp::BMPDecoder::new(r)?.dimensions(),
+        Ok(ImageFormat::ICO) => image::ico::ICODecoder::new(r)?.dimensions(),
+        Ok(ImageFormat::TIFF) => image::tiff::TIFFDecoder::new(r)?.dimensions(),
+        _ => return Ok(None),
+    };
+    Ok(Some((w as u32, h as u32)))
+}